@@ -0,0 +1,98 @@
+// args.rs: typed CNI_ARGS (like types.LoadArgs() in golang CNI)
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ArgsError {
+    #[error("invalid CNI_ARGS pair: '{0}'")]
+    InvalidPair(String),
+    #[error("unknown CNI_ARGS key: '{0}'")]
+    UnknownKey(String),
+    #[error("failed to decode CNI_ARGS: {0}")]
+    Decode(serde_json::Error),
+}
+
+impl From<serde_json::Error> for ArgsError {
+    fn from(err: serde_json::Error) -> ArgsError {
+        ArgsError::Decode(err)
+    }
+}
+
+// struct loaded from CNI_ARGS. KEYS are the keys which the struct has, and
+// other keys are error unless 'IgnoreUnknown' is given. Values are strings.
+pub trait CNIArgs: DeserializeOwned {
+    const KEYS: &'static [&'static str];
+}
+
+// standard arguments given by kubernetes runtimes
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct K8sArgs {
+    #[serde(rename = "K8S_POD_NAMESPACE", default)]
+    pub k8s_pod_namespace: Option<String>,
+    #[serde(rename = "K8S_POD_NAME", default)]
+    pub k8s_pod_name: Option<String>,
+    #[serde(rename = "K8S_POD_INFRA_CONTAINER_ID", default)]
+    pub k8s_pod_infra_container_id: Option<String>,
+    #[serde(rename = "K8S_POD_UID", default)]
+    pub k8s_pod_uid: Option<String>,
+}
+
+impl CNIArgs for K8sArgs {
+    const KEYS: &'static [&'static str] = &[
+        "K8S_POD_NAMESPACE",
+        "K8S_POD_NAME",
+        "K8S_POD_INFRA_CONTAINER_ID",
+        "K8S_POD_UID",
+    ];
+}
+
+//K=V;K2=V2; value may contain '='
+pub fn parse_args(args: &str) -> Result<Vec<(String, String)>, ArgsError> {
+    args.split(';')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((k, v)) if !k.is_empty() => Ok((k.to_string(), v.to_string())),
+            _ => Err(ArgsError::InvalidPair(pair.to_string())),
+        })
+        .collect()
+}
+
+pub fn load_args<T: CNIArgs>(args: &str) -> Result<T, ArgsError> {
+    let pairs = parse_args(args)?;
+    let ignore_unknown = pairs
+        .iter()
+        .any(|(k, v)| k == "IgnoreUnknown" && matches!(v.to_lowercase().as_str(), "1" | "true"));
+    let mut map = Map::new();
+    for (k, v) in pairs {
+        if T::KEYS.contains(&k.as_str()) {
+            map.insert(k, Value::String(v));
+        } else if k != "IgnoreUnknown" && !ignore_unknown {
+            return Err(ArgsError::UnknownKey(k));
+        }
+    }
+    Ok(serde_json::from_value(Value::Object(map))?)
+}
+
+#[test]
+fn test_load_args() {
+    let args: K8sArgs =
+        load_args("IgnoreUnknown=1;K8S_POD_NAMESPACE=default;K8S_POD_NAME=pod1;FOO=a=b").unwrap();
+    assert_eq!(args.k8s_pod_namespace.as_deref(), Some("default"));
+    assert_eq!(args.k8s_pod_name.as_deref(), Some("pod1"));
+    assert!(args.k8s_pod_uid.is_none());
+
+    assert!(matches!(
+        load_args::<K8sArgs>("K8S_POD_NAME=pod1;FOO=bar"),
+        Err(ArgsError::UnknownKey(_))
+    ));
+    assert!(matches!(
+        load_args::<K8sArgs>("K8S_POD_NAME"),
+        Err(ArgsError::InvalidPair(_))
+    ));
+    assert_eq!(
+        parse_args("K=V=1;").unwrap(),
+        vec![("K".to_string(), "V=1".to_string())]
+    );
+}
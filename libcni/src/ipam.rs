@@ -0,0 +1,113 @@
+// ipam.rs: delegate IPAM to the plugin in 'ipam.type', for main type plugins
+// (like pkg/ipam in golang CNI plugins)
+use crate::runtime::{CNIConfig, RuntimeError};
+use crate::skel::{get_result, NetConf};
+use crate::types::types_110::CNI110Result;
+use crate::types::types_common::{CNIError, ERR_INVALID_NETWORK_CONFIG};
+use anyhow::Result;
+use serde_json::Value;
+
+// 'ipam.type' of the network configuration
+pub fn get_ipam_type(netconf: &NetConf) -> Result<String> {
+    match netconf.ipam["type"].as_str() {
+        Some(t) if !t.is_empty() => Ok(t.to_string()),
+        _ => Err(CNIError::new(ERR_INVALID_NETWORK_CONFIG, "missing 'ipam.type'", "").into()),
+    }
+}
+
+// run the IPAM plugin with the environment of this process except CNI_COMMAND,
+// and stdin (network configuration) given to this plugin. CNIError from the
+// IPAM plugin is returned as is, so that plugin_main() keeps its code.
+pub fn exec_ipam(cni: &CNIConfig, command: &str, plugin_type: &str, stdin: &str) -> Result<String> {
+    match cni.run_plugin(plugin_type, &[("CNI_COMMAND", command.to_string())], stdin) {
+        Ok(output) => Ok(output),
+        Err(RuntimeError::Plugin(_, err)) => Err(err.into()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// ADD by the IPAM plugin in CNI_PATH. The result is returned in latest version
+// (not in cniVersion of the configuration), so that the caller can modify it
+// and convert it back with get_result_output(), like other results in libcni.
+pub fn exec_add(plugin_type: &str, stdin: &str) -> Result<CNI110Result> {
+    ipam_add(&CNIConfig::from_env(), plugin_type, stdin)
+}
+
+pub fn exec_check(plugin_type: &str, stdin: &str) -> Result<()> {
+    ipam_check(&CNIConfig::from_env(), plugin_type, stdin)
+}
+
+pub fn exec_del(plugin_type: &str, stdin: &str) -> Result<()> {
+    ipam_del(&CNIConfig::from_env(), plugin_type, stdin)
+}
+
+// exec_add/exec_check/exec_del with the given plugin path
+fn ipam_add(cni: &CNIConfig, plugin_type: &str, stdin: &str) -> Result<CNI110Result> {
+    let output = exec_ipam(cni, "ADD", plugin_type, stdin)?;
+    let netconf: Value = serde_json::from_str(stdin)?;
+    get_result(
+        netconf["cniVersion"].as_str().unwrap_or(""),
+        &serde_json::from_str(&output)?,
+    )
+}
+
+fn ipam_check(cni: &CNIConfig, plugin_type: &str, stdin: &str) -> Result<()> {
+    exec_ipam(cni, "CHECK", plugin_type, stdin).map(|_| ())
+}
+
+fn ipam_del(cni: &CNIConfig, plugin_type: &str, stdin: &str) -> Result<()> {
+    exec_ipam(cni, "DEL", plugin_type, stdin).map(|_| ())
+}
+
+#[test]
+fn test_exec_ipam() {
+    use crate::types::types_common::ERR_TRY_AGAIN_LATER;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("libcni-ipam-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let script = r#"#!/bin/sh
+case "$CNI_COMMAND" in
+ADD) echo '{"cniVersion":"0.4.0","ips":[{"version":"4","address":"10.1.1.1/24"}]}' ;;
+CHECK) ;;
+IFNAME) echo "{\"ifname\":\"$CNI_IFNAME\"}" ;;
+*) echo '{"code":11,"msg":"try again"}'; exit 1 ;;
+esac
+"#;
+    let path = dir.join("test-ipam");
+    fs::write(&path, script).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let netconf: NetConf =
+        serde_json::from_str(r#"{"cniVersion": "0.4.0", "ipam": {"type": "test-ipam"}}"#).unwrap();
+    let ipam_type = get_ipam_type(&netconf).unwrap();
+    let cni = CNIConfig::new(vec![dir.clone()]);
+    let result = ipam_add(&cni, &ipam_type, r#"{"cniVersion": "0.4.0"}"#).unwrap();
+    assert_eq!(result.cni_version, "1.1.0");
+    assert_eq!(result.ips[0].address.to_string(), "10.1.1.1/24");
+    assert!(ipam_check(&cni, &ipam_type, "{}").is_ok());
+
+    let err = ipam_del(&cni, &ipam_type, "{}").unwrap_err();
+    assert_eq!(
+        err.downcast::<CNIError>().unwrap().code,
+        ERR_TRY_AGAIN_LATER
+    );
+
+    // other CNI_* variables reach the IPAM plugin along with CNI_COMMAND
+    let output = cni
+        .run_plugin(
+            &ipam_type,
+            &[
+                ("CNI_COMMAND", "IFNAME".to_string()),
+                ("CNI_IFNAME", "net1".to_string()),
+            ],
+            "{}",
+        )
+        .unwrap();
+    assert_eq!(output.trim(), r#"{"ifname":"net1"}"#);
+
+    let netconf: NetConf = serde_json::from_str(r#"{"ipam": {}}"#).unwrap();
+    assert!(get_ipam_type(&netconf).is_err());
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -1,3 +1,7 @@
+pub mod args;
+pub mod ipam;
 pub mod ipnet;
+pub mod runtime;
 pub mod skel;
 pub mod types;
+pub mod version;
@@ -0,0 +1,381 @@
+// runtime.rs: runtime side of CNI, which loads network configuration and
+// invokes plugins (like libcni in golang CNI)
+use crate::skel::get_result;
+use crate::types::types_110::CNI110Result;
+use crate::types::types_common::{CNIError, ERR_INTERNAL};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::{env, fs, io};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RuntimeError {
+    #[error("failed to read/exec: {0}")]
+    Io(io::Error),
+    #[error("failed to parse json: {0}")]
+    Json(serde_json::Error),
+    #[error("invalid network configuration: {0}")]
+    InvalidConfig(String),
+    #[error("failed to find plugin '{0}' in path {1:?}")]
+    PluginNotFound(String, Vec<PathBuf>),
+    #[error("plugin '{0}' failed: {1}")]
+    Plugin(String, CNIError),
+    #[error("invalid result of plugin '{0}': {1}")]
+    InvalidResult(String, anyhow::Error),
+}
+
+impl From<io::Error> for RuntimeError {
+    fn from(err: io::Error) -> RuntimeError {
+        RuntimeError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for RuntimeError {
+    fn from(err: serde_json::Error) -> RuntimeError {
+        RuntimeError::Json(err)
+    }
+}
+
+// network configuration list (.conflist). '.conf' is loaded as list with one plugin
+#[derive(Deserialize, Debug, Clone)]
+pub struct NetworkConfigList {
+    #[serde(rename = "cniVersion", default)]
+    pub cni_version: String,
+    #[serde(rename = "name", default)]
+    pub name: String,
+    #[serde(rename = "disableCheck", default)]
+    pub disable_check: bool,
+    /// plugins keeps each plugin configuration as is, to pass unknown keys to plugin
+    #[serde(rename = "plugins", default)]
+    pub plugins: Vec<Value>,
+}
+
+impl NetworkConfigList {
+    pub fn from_conflist(bytes: &str) -> Result<NetworkConfigList, RuntimeError> {
+        let list: NetworkConfigList = serde_json::from_str(bytes)?;
+        list.validate()?;
+        Ok(list)
+    }
+
+    fn validate(&self) -> Result<(), RuntimeError> {
+        if self.name.is_empty() {
+            return Err(RuntimeError::InvalidConfig("no name".to_string()));
+        }
+        if self.plugins.is_empty() {
+            return Err(RuntimeError::InvalidConfig(
+                "no plugins in list".to_string(),
+            ));
+        }
+        for plugin in self.plugins.iter() {
+            if plugin["type"].as_str().unwrap_or("").is_empty() {
+                return Err(RuntimeError::InvalidConfig(
+                    "plugin without type".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn from_conf(bytes: &str) -> Result<NetworkConfigList, RuntimeError> {
+        let conf: Value = serde_json::from_str(bytes)?;
+        // missing name/cniVersion is left empty (not null) and checked below
+        let list = NetworkConfigList {
+            cni_version: conf["cniVersion"].as_str().unwrap_or_default().to_string(),
+            name: conf["name"].as_str().unwrap_or_default().to_string(),
+            disable_check: false,
+            plugins: vec![conf],
+        };
+        list.validate()?;
+        Ok(list)
+    }
+
+    // load .conflist or .conf (.json) file
+    pub fn from_file(path: &Path) -> Result<NetworkConfigList, RuntimeError> {
+        let bytes = fs::read_to_string(path)?;
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("conflist") => NetworkConfigList::from_conflist(&bytes),
+            Some("conf") | Some("json") => NetworkConfigList::from_conf(&bytes),
+            _ => Err(RuntimeError::InvalidConfig(format!(
+                "unknown file type: {}",
+                path.display()
+            ))),
+        }
+    }
+
+    // configuration passed to the plugin: name/cniVersion of the list,
+    // prevResult and runtimeConfig for the capabilities which plugin has
+    pub fn get_plugin_config(
+        &self,
+        plugin: &Value,
+        prev_result: Option<&Value>,
+        rt: &RuntimeConf,
+    ) -> Value {
+        let mut conf = plugin.clone();
+        conf["name"] = Value::String(self.name.clone());
+        conf["cniVersion"] = Value::String(self.cni_version.clone());
+        if let Some(prev_result) = prev_result {
+            conf["prevResult"] = prev_result.clone();
+        }
+        if let Some(capabilities) = plugin["capabilities"].as_object() {
+            let runtime_config: serde_json::Map<String, Value> = capabilities
+                .iter()
+                .filter(|(_, enabled)| enabled.as_bool().unwrap_or(false))
+                .filter_map(|(k, _)| rt.capability_args.get(k).map(|v| (k.clone(), v.clone())))
+                .collect();
+            if !runtime_config.is_empty() {
+                conf["runtimeConfig"] = Value::Object(runtime_config);
+            }
+        }
+        conf
+    }
+}
+
+// per-invocation parameters from the runtime
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeConf {
+    pub container_id: String,
+    pub netns: String,
+    pub ifname: String,
+    /// args are passed in CNI_ARGS, e.g. K8S_POD_NAME
+    pub args: Vec<(String, String)>,
+    /// capability_args are passed in runtimeConfig if plugin has the capability
+    pub capability_args: HashMap<String, Value>,
+}
+
+// executes plugins found in 'path' (CNI_PATH)
+#[derive(Debug, Clone)]
+pub struct CNIConfig {
+    pub path: Vec<PathBuf>,
+}
+
+impl CNIConfig {
+    pub fn new(path: Vec<PathBuf>) -> CNIConfig {
+        CNIConfig { path }
+    }
+
+    pub fn from_env() -> CNIConfig {
+        CNIConfig::new(env::split_paths(&env::var_os("CNI_PATH").unwrap_or_default()).collect())
+    }
+
+    pub fn find_plugin(&self, plugin_type: &str) -> Result<PathBuf, RuntimeError> {
+        self.path
+            .iter()
+            .map(|dir| dir.join(plugin_type))
+            .find(|p| p.is_file())
+            .ok_or_else(|| RuntimeError::PluginNotFound(plugin_type.to_string(), self.path.clone()))
+    }
+
+    // run the plugin binary and returns its stdout
+    pub fn exec_plugin(
+        &self,
+        command: &str,
+        plugin_type: &str,
+        conf: &Value,
+        rt: &RuntimeConf,
+    ) -> Result<String, RuntimeError> {
+        let cni_args = rt
+            .args
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<String>>()
+            .join(";");
+        let envs = [
+            ("CNI_COMMAND", command.to_string()),
+            ("CNI_CONTAINERID", rt.container_id.clone()),
+            ("CNI_NETNS", rt.netns.clone()),
+            ("CNI_IFNAME", rt.ifname.clone()),
+            ("CNI_ARGS", cni_args),
+            (
+                "CNI_PATH",
+                env::join_paths(&self.path)
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+        ];
+        self.run_plugin(plugin_type, &envs, &conf.to_string())
+    }
+
+    // run the plugin with 'envs' in addition to the environment of this process
+    pub fn run_plugin(
+        &self,
+        plugin_type: &str,
+        envs: &[(&str, String)],
+        stdin: &str,
+    ) -> Result<String, RuntimeError> {
+        let plugin_path = self.find_plugin(plugin_type)?;
+        let mut child = Command::new(&plugin_path)
+            .envs(envs.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin_pipe) = child.stdin.take() {
+            // plugin may exit without reading stdin
+            match stdin_pipe.write_all(stdin.as_bytes()) {
+                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+                _ => {}
+            }
+        }
+        let output = child.wait_with_output()?;
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        if !output.status.success() {
+            let err = serde_json::from_str::<CNIError>(&stdout).unwrap_or_else(|_| {
+                CNIError::new(
+                    ERR_INTERNAL,
+                    &format!("plugin exited with {}", output.status),
+                    String::from_utf8_lossy(&output.stderr).trim(),
+                )
+            });
+            return Err(RuntimeError::Plugin(plugin_type.to_string(), err));
+        }
+        Ok(stdout)
+    }
+
+    fn exec_list(
+        &self,
+        command: &str,
+        list: &NetworkConfigList,
+        plugin: &Value,
+        prev_result: Option<&Value>,
+        rt: &RuntimeConf,
+    ) -> Result<String, RuntimeError> {
+        let plugin_type = plugin["type"].as_str().unwrap_or("");
+        let conf = list.get_plugin_config(plugin, prev_result, rt);
+        self.exec_plugin(command, plugin_type, &conf, rt)
+    }
+
+    // ADD each plugin in order, passing the result to the next plugin as prevResult
+    pub fn add_network_list(
+        &self,
+        list: &NetworkConfigList,
+        rt: &RuntimeConf,
+    ) -> Result<CNI110Result, RuntimeError> {
+        let mut prev_result: Option<Value> = None;
+        for plugin in list.plugins.iter() {
+            let output = self.exec_list("ADD", list, plugin, prev_result.as_ref(), rt)?;
+            prev_result = Some(serde_json::from_str(&output)?);
+        }
+        let result = prev_result.unwrap_or(Value::Null);
+        get_result(&list.cni_version, &result)
+            .map_err(|e| RuntimeError::InvalidResult(list.name.clone(), e))
+    }
+
+    // CHECK each plugin in order with the result of ADD
+    pub fn check_network_list(
+        &self,
+        list: &NetworkConfigList,
+        prev_result: &Value,
+        rt: &RuntimeConf,
+    ) -> Result<(), RuntimeError> {
+        if list.disable_check {
+            return Ok(());
+        }
+        for plugin in list.plugins.iter() {
+            self.exec_list("CHECK", list, plugin, Some(prev_result), rt)?;
+        }
+        Ok(())
+    }
+
+    // DEL each plugin in reverse order
+    pub fn del_network_list(
+        &self,
+        list: &NetworkConfigList,
+        prev_result: Option<&Value>,
+        rt: &RuntimeConf,
+    ) -> Result<(), RuntimeError> {
+        for plugin in list.plugins.iter().rev() {
+            self.exec_list("DEL", list, plugin, prev_result, rt)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_load_conflist() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../example/99-differance.conflist");
+    let list = NetworkConfigList::from_file(&path).unwrap();
+    assert_eq!(list.name, "test");
+    assert_eq!(list.plugins[0]["type"], "macvlan");
+
+    let rt = RuntimeConf {
+        capability_args: [("ips".to_string(), serde_json::json!(["10.1.1.1/24"]))]
+            .into_iter()
+            .collect(),
+        ..Default::default()
+    };
+    let conf = list.get_plugin_config(&list.plugins[0], Some(&serde_json::json!({})), &rt);
+    assert_eq!(conf["cniVersion"], "0.4.0");
+    assert_eq!(conf["name"], "test");
+    assert_eq!(conf["runtimeConfig"]["ips"][0], "10.1.1.1/24");
+    assert!(conf.get("prevResult").is_some());
+
+    let list =
+        NetworkConfigList::from_conf(r#"{"cniVersion": "1.0.0", "name": "n", "type": "bridge"}"#)
+            .unwrap();
+    assert_eq!(
+        (list.cni_version.as_str(), list.plugins.len()),
+        ("1.0.0", 1)
+    );
+    assert!(NetworkConfigList::from_conflist(r#"{"name": "n", "plugins": []}"#).is_err());
+    assert!(matches!(
+        NetworkConfigList::from_conf(r#"{"cniVersion": "1.0.0", "type": "bridge"}"#),
+        Err(RuntimeError::InvalidConfig(_))
+    ));
+}
+
+#[test]
+fn test_add_network_list() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = env::temp_dir().join(format!("libcni-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // first plugin returns an address, second one saves its stdin
+    let plugins = [
+        ("first", r#"echo '{"cniVersion":"1.0.0","ips":[{"address":"10.1.1.1/24"}]}'"#.to_string()),
+        ("second", format!("cat > {}/stdin; echo '{{\"cniVersion\":\"1.0.0\",\"ips\":[{{\"address\":\"10.1.1.2/24\"}}]}}'", dir.display())),
+        ("fail", r#"echo '{"code":11,"msg":"try again"}'; exit 1"#.to_string()),
+    ];
+    for (name, script) in plugins.iter() {
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let cni = CNIConfig::new(vec![dir.clone()]);
+    let rt = RuntimeConf {
+        container_id: "abcd".to_string(),
+        netns: "/var/run/netns/test".to_string(),
+        ifname: "net1".to_string(),
+        ..Default::default()
+    };
+    let list = NetworkConfigList::from_conflist(
+        r#"{"cniVersion": "1.0.0", "name": "n", "plugins": [{"type": "first"}, {"type": "second"}]}"#,
+    )
+    .unwrap();
+    let result = cni.add_network_list(&list, &rt).unwrap();
+    assert_eq!(result.ips[0].address.to_string(), "10.1.1.2/24");
+    let stdin: Value =
+        serde_json::from_str(&fs::read_to_string(dir.join("stdin")).unwrap()).unwrap();
+    assert_eq!(stdin["prevResult"]["ips"][0]["address"], "10.1.1.1/24");
+
+    let list =
+        NetworkConfigList::from_conf(r#"{"cniVersion": "1.0.0", "name": "n", "type": "fail"}"#)
+            .unwrap();
+    match cni.add_network_list(&list, &rt) {
+        Err(RuntimeError::Plugin(name, err)) => assert_eq!((name.as_str(), err.code), ("fail", 11)),
+        r => panic!("unexpected: {:?}", r.map(|_| ())),
+    }
+    let list =
+        NetworkConfigList::from_conf(r#"{"cniVersion": "1.0.0", "name": "n", "type": "none"}"#)
+            .unwrap();
+    assert!(matches!(
+        cni.del_network_list(&list, None, &rt),
+        Err(RuntimeError::PluginNotFound(_, _))
+    ));
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -1,10 +1,14 @@
 // skel.rs: which contains CmdArgs/NetConf structure for CNI
 // 2022, Tomofumi Hayashi
+use crate::args::{load_args, ArgsError, CNIArgs};
 use crate::types::types_020::CNI020Result;
 use crate::types::types_040::CNI040Result;
 use crate::types::types_100::CNI100Result;
-use crate::types::types_common::DNS;
+use crate::types::types_110::{CNI110Result, ResultValidationError};
+use crate::types::types_common::*;
+use crate::version::{PluginInfo, DEFAULT_VERSION};
 use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde_json::Value;
 use std::collections::HashMap;
@@ -19,6 +23,15 @@ pub struct CmdArgs {
     pub args: HashMap<String, String>,
     pub path: String,
     pub stdin_data: String,
+    /// args_data is CNI_ARGS as is, for load_args()
+    pub args_data: String,
+}
+
+impl CmdArgs {
+    // typed CNI_ARGS, e.g. cmd_args.load_args::<K8sArgs>()
+    pub fn load_args<T: CNIArgs>(&self) -> Result<T, ArgsError> {
+        load_args(&self.args_data)
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -47,38 +60,50 @@ pub struct NetConf {
     #[allow(unused)]
     #[serde(rename = "prevResult", default)]
     pub prev_result: serde_json::Value,
+    /// args specifies arguments from the runtime in the config, e.g. 'args.cni'
+    #[allow(unused)]
+    #[serde(rename = "args", default)]
+    pub args: serde_json::Value,
+    /// runtime_config specifies values for capabilities, injected by the runtime
+    #[allow(unused)]
+    #[serde(rename = "runtimeConfig", default)]
+    pub runtime_config: serde_json::Value,
+    /// valid_attachments specifies attachments which GC must keep
+    #[allow(unused)]
+    #[serde(rename = "cni.dev/valid-attachments", default)]
+    pub valid_attachments: Vec<GCAttachment>,
+}
+
+// attachment in 'cni.dev/valid-attachments' of GC
+#[derive(Deserialize, Debug, Clone)]
+pub struct GCAttachment {
+    #[serde(rename = "containerID")]
+    pub container_id: String,
+    #[serde(rename = "ifname")]
+    pub ifname: String,
 }
 
 impl NetConf {
-    //strategy in golang CNI
-    //- check cniVersion and put into Result (if there is no cniVersion in result)
-    //- regenerate bytes
-    //- parse it again based on above cniVersion
-    pub fn get_current_result(&self) -> Result<CNI100Result> {
-        let cni_version = match self.prev_result["cniVersion"] {
-            Value::Null => self.cni_version.clone(),
-            _ => self.prev_result["cniVersion"].to_string().replace('\"', ""),
-        };
-        let mut result = self.prev_result.clone();
-        result["cniVersion"] = serde_json::Value::String(cni_version.clone());
-        let result_str_buf = serde_json::to_string(&result)?; // need to handle error
-        match cni_version.as_str() {
-            "0.1.0" | "0.2.0" => Ok(
-                serde_json::from_str::<CNI020Result>(result_str_buf.as_str())?.convert_to_latest(),
-            ),
-            "0.3.0" | "0.3.1" | "0.4.0" => Ok(serde_json::from_str::<CNI040Result>(
-                result_str_buf.as_str(),
-            )?
-            .convert_to_latest()),
-            "1.0.0" => Ok(serde_json::from_str::<CNI100Result>(
-                result_str_buf.as_str(),
-            )?),
-            _ => Err(anyhow!("failed")),
+    // runtimeConfig as 'T', default if the runtime gives nothing
+    pub fn get_runtime_config<T: DeserializeOwned + Default>(
+        &self,
+    ) -> Result<T, serde_json::Error> {
+        match self.runtime_config {
+            Value::Null => Ok(T::default()),
+            _ => serde_json::from_value(self.runtime_config.clone()),
         }
     }
 
-    pub fn get_result_output(&self, result: &CNI100Result) -> Result<String, ResultError> {
-        let cni_version = self.cni_version.to_string().replace('\"', "");
+    pub fn get_current_result(&self) -> Result<CNI110Result> {
+        get_result(&self.cni_version, &self.prev_result)
+    }
+
+    pub fn get_result_output(&self, result: &CNI110Result) -> Result<String, ResultError> {
+        result.validate()?;
+        let cni_version = match self.cni_version.as_str() {
+            "" => DEFAULT_VERSION.to_string(),
+            v => v.replace('\"', ""),
+        };
 
         match cni_version.as_str() {
             "0.1.0" | "0.2.0" => Ok(serde_json::to_string(&CNI020Result::convert_from_latest(
@@ -88,12 +113,49 @@ impl NetConf {
             "0.3.0" | "0.3.1" | "0.4.0" => Ok(serde_json::to_string(
                 &CNI040Result::convert_from_latest(result, cni_version.as_str()),
             )?),
-            "1.0.0" => Ok(serde_json::to_string(&result)?),
+            "1.0.0" => Ok(serde_json::to_string(&CNI100Result::convert_from_latest(
+                result,
+                cni_version.as_str(),
+            ))?),
+            "1.1.0" => Ok(serde_json::to_string(&result)?),
             err => Err(ResultError::CNIVersionError(anyhow!("failed: {}", err))),
         }
     }
 }
 
+// parse the result in 'cni_version' (or its own cniVersion) and convert to latest
+//strategy in golang CNI
+//- check cniVersion and put into Result (if there is no cniVersion in result)
+//- regenerate bytes
+//- parse it again based on above cniVersion
+pub fn get_result(cni_version: &str, result: &Value) -> Result<CNI110Result> {
+    let cni_version = match (&result["cniVersion"], cni_version) {
+        (Value::Null, "") => DEFAULT_VERSION.to_string(),
+        (Value::Null, _) => cni_version.to_string(),
+        _ => result["cniVersion"].to_string().replace('\"', ""),
+    };
+    let mut result = result.clone();
+    result["cniVersion"] = serde_json::Value::String(cni_version.clone());
+    let result_str_buf = serde_json::to_string(&result)?; // need to handle error
+    match cni_version.as_str() {
+        "0.1.0" | "0.2.0" => Ok(
+            serde_json::from_str::<CNI020Result>(result_str_buf.as_str())?.convert_to_latest(),
+        ),
+        "0.3.0" | "0.3.1" | "0.4.0" => Ok(serde_json::from_str::<CNI040Result>(
+            result_str_buf.as_str(),
+        )?
+        .convert_to_latest()),
+        "1.0.0" => Ok(serde_json::from_str::<CNI100Result>(
+            result_str_buf.as_str(),
+        )?
+        .convert_to_latest()),
+        "1.1.0" => Ok(serde_json::from_str::<CNI110Result>(
+            result_str_buf.as_str(),
+        )?),
+        v => Err(anyhow!("unsupported cniVersion: {}", v)),
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum CmdArgsError<'a> {
     #[error("failed to read stdin: {0}")]
@@ -112,9 +174,29 @@ pub fn get_cmdargs_env<'a>(
     command: &str,
     arg_name: &'a str,
     required_command: (bool, bool, bool),
+) -> Result<String, CmdArgsError<'a>> {
+    check_cmdargs_var(command, arg_name, required_command, env::var(arg_name))
+}
+
+// same as get_cmdargs_env, but reads the variable from 'vars' instead of environment
+pub fn get_cmdargs_var<'a>(
+    vars: &HashMap<String, String>,
+    command: &str,
+    arg_name: &'a str,
+    required_command: (bool, bool, bool),
+) -> Result<String, CmdArgsError<'a>> {
+    let value = vars.get(arg_name).cloned().ok_or(env::VarError::NotPresent);
+    check_cmdargs_var(command, arg_name, required_command, value)
+}
+
+fn check_cmdargs_var<'a>(
+    command: &str,
+    arg_name: &'a str,
+    required_command: (bool, bool, bool),
+    value: Result<String, env::VarError>,
 ) -> Result<String, CmdArgsError<'a>> {
     let (add, check, del) = required_command;
-    Ok(match env::var(arg_name) {
+    Ok(match value {
         Ok(v) => v,
         Err(err) => {
             if command == "ADD" && add {
@@ -131,19 +213,24 @@ pub fn get_cmdargs_env<'a>(
     })
 }
 
-//K=V;K2=V2;
+//K=V;K2=V2; use load_args() to validate the keys and pairs
 pub fn get_args(args: &str) -> HashMap<String, String> {
-    let mut args_map = HashMap::new();
-
-    args.split(';').for_each(|r| {
-        let v: Vec<&str> = r.split('=').collect();
-        match v.len() {
-            1 => args_map.insert(v[0].clone().to_string(), "".to_string()),
-            2 => args_map.insert(v[0].clone().to_string(), v[1].clone().to_string()),
-            _ => None,
-        };
-    });
-    args_map
+    args.split(';')
+        .filter(|r| !r.is_empty())
+        .map(|r| match r.split_once('=') {
+            Some((k, v)) => (k.to_string(), v.to_string()),
+            None => (r.to_string(), "".to_string()),
+        })
+        .collect()
+}
+
+#[test]
+fn test_get_result_default_version() {
+    // cniVersion is omitted, as check_compatible() accepts
+    let netconf = get_netconf("{}").unwrap();
+    let result = netconf.get_current_result().unwrap();
+    let output: Value = serde_json::from_str(&netconf.get_result_output(&result).unwrap()).unwrap();
+    assert_eq!(output["cniVersion"], DEFAULT_VERSION);
 }
 
 #[test]
@@ -158,7 +245,7 @@ fn test_get_args() {
 fn test_get_args2() {
     let args_map = get_args("K=V;K2=V2;");
 
-    assert_eq!(args_map.get(&"K".to_string()).unwrap(), "V");
+    assert_eq!(args_map.get("K").unwrap(), "V");
     assert_eq!(args_map[&"K".to_string()], "V");
     assert_eq!(args_map[&"K2".to_string()], "V2");
 }
@@ -172,17 +259,66 @@ pub fn get_cmdargs() -> Result<(String, CmdArgs), CmdArgsError<'static>> {
         Err(err) => return Err(CmdArgsError::MissingArgs("CNI_COMMAND", err)),
     };
 
+    let args_data = get_cmdargs_env(command.as_str(), "CNI_ARGS", (false, false, false))?;
     let args = CmdArgs {
         container_id: get_cmdargs_env(command.as_str(), "CNI_CONTAINERID", (true, true, true))?,
         netns: get_cmdargs_env(command.as_str(), "CNI_NETNS", (true, true, false))?,
         ifname: get_cmdargs_env(command.as_str(), "CNI_IFNAME", (true, true, true))?,
-        args: get_args(&get_cmdargs_env(command.as_str(), "CNI_ARGS", (false, false, false))?),
+        args: get_args(&args_data),
         path: get_cmdargs_env(command.as_str(), "CNI_PATH", (true, true, true))?,
         stdin_data: stdin,
+        args_data,
+    };
+    Ok((command, args))
+}
+
+// CmdArgs from CNI_* variables in 'vars', e.g. forwarded from another process
+pub fn get_cmdargs_vars(
+    vars: &HashMap<String, String>,
+    stdin: String,
+) -> Result<(String, CmdArgs), CmdArgsError<'static>> {
+    let command = match vars.get("CNI_COMMAND") {
+        Some(v) => v.clone(),
+        None => return Err(CmdArgsError::MissingArgs("CNI_COMMAND", env::VarError::NotPresent)),
+    };
+
+    let args_data = get_cmdargs_var(vars, command.as_str(), "CNI_ARGS", (false, false, false))?;
+    let args = CmdArgs {
+        container_id: get_cmdargs_var(vars, command.as_str(), "CNI_CONTAINERID", (true, true, true))?,
+        netns: get_cmdargs_var(vars, command.as_str(), "CNI_NETNS", (true, true, false))?,
+        ifname: get_cmdargs_var(vars, command.as_str(), "CNI_IFNAME", (true, true, true))?,
+        args: get_args(&args_data),
+        path: get_cmdargs_var(vars, command.as_str(), "CNI_PATH", (true, true, true))?,
+        stdin_data: stdin,
+        args_data,
     };
     Ok((command, args))
 }
 
+#[test]
+fn test_get_cmdargs_vars() {
+    let vars: HashMap<String, String> = [
+        ("CNI_COMMAND", "DEL"),
+        ("CNI_CONTAINERID", "abcd"),
+        ("CNI_IFNAME", "net1"),
+        ("CNI_ARGS", "K8S_POD_NAME=pod1"),
+        ("CNI_PATH", "/opt/cni/bin"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+    let (command, args) = get_cmdargs_vars(&vars, "{}".to_string()).unwrap();
+    assert_eq!(command, "DEL");
+    assert_eq!(args.netns, "");
+    assert_eq!(args.args["K8S_POD_NAME"], "pod1");
+    let k8s_args: crate::args::K8sArgs = args.load_args().unwrap();
+    assert_eq!(k8s_args.k8s_pod_name.as_deref(), Some("pod1"));
+
+    let mut vars = vars;
+    vars.insert("CNI_COMMAND".to_string(), "ADD".to_string());
+    assert!(get_cmdargs_vars(&vars, "{}".to_string()).is_err());
+}
+
 pub fn get_netconf(stdin_str: &str) -> Result<NetConf, serde_json::Error> {
     serde_json::from_str(stdin_str)
 }
@@ -193,6 +329,8 @@ pub enum ResultError {
     JsonEncodeError(serde_json::Error),
     #[error("failed to get cniVersion: {0}")]
     CNIVersionError(anyhow::Error),
+    #[error("invalid result: {0}")]
+    InvalidResult(ResultValidationError),
 }
 
 impl From<serde_json::Error> for ResultError {
@@ -201,8 +339,194 @@ impl From<serde_json::Error> for ResultError {
     }
 }
 
+impl From<ResultValidationError> for ResultError {
+    fn from(err: ResultValidationError) -> ResultError {
+        ResultError::InvalidResult(err)
+    }
+}
+
 impl From<anyhow::Error> for ResultError {
     fn from(err: anyhow::Error) -> ResultError {
         ResultError::CNIVersionError(err)
     }
 }
+
+// Plugin is implemented by CNI plugins and is called from plugin_main().
+// Errors may be CNIError (wrapped in anyhow) to return specific error code,
+// otherwise they are returned as internal error.
+pub trait Plugin {
+    fn add(&self, args: &CmdArgs, netconf: &NetConf) -> Result<CNI110Result>;
+    fn check(&self, args: &CmdArgs, netconf: &NetConf) -> Result<()>;
+    fn del(&self, args: &CmdArgs, netconf: &NetConf) -> Result<()>;
+    fn gc(&self, _args: &CmdArgs, _netconf: &NetConf) -> Result<()> {
+        Ok(())
+    }
+    fn status(&self, _args: &CmdArgs, _netconf: &NetConf) -> Result<()> {
+        Ok(())
+    }
+}
+
+// CNIError in 'err' is kept as is, and other errors are ERR_INTERNAL
+pub fn to_cni_error(err: anyhow::Error, cni_version: &str) -> CNIError {
+    let mut cni_err = match err.downcast::<CNIError>() {
+        Ok(e) => e,
+        Err(e) => CNIError::new(ERR_INTERNAL, &e.to_string(), ""),
+    };
+    if cni_err.cni_version.is_empty() {
+        cni_err.cni_version = cni_version.to_string();
+    }
+    cni_err
+}
+
+// dispatch the command to the plugin and returns the output for stdout
+pub fn plugin_dispatch<P: Plugin + ?Sized>(
+    plugin: &P,
+    info: &PluginInfo,
+    command: &str,
+    args: &CmdArgs,
+) -> Result<String, CNIError> {
+    if command == "VERSION" {
+        return info.get_version_output(&args.stdin_data);
+    }
+    let netconf = get_netconf(&args.stdin_data).map_err(|e| {
+        CNIError::new(
+            ERR_DECODING_FAILURE,
+            "failed to parse network config",
+            &e.to_string(),
+        )
+    })?;
+    info.check_compatible(command, &netconf)?;
+    let cni_version = netconf.cni_version.as_str();
+    match command {
+        "ADD" => plugin
+            .add(args, &netconf)
+            .and_then(|result| Ok(netconf.get_result_output(&result)?)),
+        "CHECK" => plugin.check(args, &netconf).map(|_| String::new()),
+        "DEL" => plugin.del(args, &netconf).map(|_| String::new()),
+        "GC" => plugin.gc(args, &netconf).map(|_| String::new()),
+        "STATUS" => plugin.status(args, &netconf).map(|_| String::new()),
+        _ => {
+            let mut err = CNIError::new(
+                ERR_INVALID_ENVIRONMENT_VARIABLES,
+                &format!("unknown CNI_COMMAND: {}", command),
+                "",
+            );
+            err.cni_version = cni_version.to_string();
+            return Err(err);
+        }
+    }
+    .map_err(|e| to_cni_error(e, cni_version))
+}
+
+// entry point of CNI plugin, like PluginMain() of golang skel. Prints the
+// result (or error) to stdout and exits with 1 on error.
+pub fn plugin_main<P: Plugin + ?Sized>(plugin: &P, info: &PluginInfo, about: &str) {
+    if env::var("CNI_COMMAND").unwrap_or_default().is_empty() && !about.is_empty() {
+        eprintln!("{}", about);
+        return;
+    }
+    let output = match get_cmdargs() {
+        Ok((command, args)) => plugin_dispatch(plugin, info, &command, &args),
+        Err(CmdArgsError::FailedReadStdIn(e)) => Err(CNIError::new(
+            ERR_IO_FAILURE,
+            "failed to read stdin",
+            &e.to_string(),
+        )),
+        Err(e) => Err(CNIError::new(
+            ERR_INVALID_ENVIRONMENT_VARIABLES,
+            &e.to_string(),
+            "",
+        )),
+    };
+    match output {
+        Ok(output) => {
+            if !output.is_empty() {
+                println!("{}", output);
+            }
+        }
+        Err(err) => {
+            println!("{}", serde_json::to_string(&err).unwrap_or_default());
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+struct TestPlugin;
+
+#[cfg(test)]
+impl Plugin for TestPlugin {
+    fn add(&self, _args: &CmdArgs, netconf: &NetConf) -> Result<CNI110Result> {
+        netconf.get_current_result()
+    }
+    fn check(&self, _args: &CmdArgs, _netconf: &NetConf) -> Result<()> {
+        Err(anyhow!("check failed"))
+    }
+    fn del(&self, _args: &CmdArgs, _netconf: &NetConf) -> Result<()> {
+        Err(CNIError::new(ERR_UNKNOWN_CONTAINER, "no such container", "abcd").into())
+    }
+}
+
+#[test]
+fn test_plugin_dispatch() {
+    let vars: HashMap<String, String> = [
+        ("CNI_COMMAND", "ADD"),
+        ("CNI_CONTAINERID", "abcd"),
+        ("CNI_NETNS", "/var/run/netns/test"),
+        ("CNI_IFNAME", "net1"),
+        ("CNI_PATH", "/opt/cni/bin"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+    let stdin = r#"{
+        "cniVersion": "0.4.0",
+        "name": "test",
+        "type": "test",
+        "prevResult": { "ips": [{ "version": "4", "address": "10.1.1.1/24" }] }
+    }"#;
+    let (_, args) = get_cmdargs_vars(&vars, stdin.to_string()).unwrap();
+    let info = PluginInfo::all();
+
+    let output: Value =
+        serde_json::from_str(&plugin_dispatch(&TestPlugin, &info, "ADD", &args).unwrap()).unwrap();
+    assert_eq!(output["cniVersion"], "0.4.0");
+    assert_eq!(output["ips"][0]["version"], "4");
+
+    let err = plugin_dispatch(&TestPlugin, &info, "CHECK", &args).unwrap_err();
+    assert_eq!(
+        (err.code, err.cni_version.as_str()),
+        (ERR_INTERNAL, "0.4.0")
+    );
+    let err = plugin_dispatch(&TestPlugin, &info, "DEL", &args).unwrap_err();
+    assert_eq!(
+        (err.code, err.details.as_str()),
+        (ERR_UNKNOWN_CONTAINER, "abcd")
+    );
+    let err = plugin_dispatch(&TestPlugin, &info, "GC", &args).unwrap_err();
+    assert_eq!(err.code, ERR_INCOMPATIBLE_CNI_VERSION);
+    let err = plugin_dispatch(&TestPlugin, &info, "FOO", &args).unwrap_err();
+    assert_eq!(err.code, ERR_INVALID_ENVIRONMENT_VARIABLES);
+}
+
+#[test]
+fn test_runtime_config() {
+    #[derive(Deserialize, Default)]
+    struct RuntimeConfig {
+        #[serde(default)]
+        ips: Vec<String>,
+    }
+    let netconf = get_netconf(
+        r#"{
+        "cniVersion": "1.0.0",
+        "args": { "cni": { "ips": ["10.1.1.2/24"] } },
+        "runtimeConfig": { "ips": ["10.1.1.1/24"] }
+    }"#,
+    )
+    .unwrap();
+    let config: RuntimeConfig = netconf.get_runtime_config().unwrap();
+    assert_eq!(config.ips, vec!["10.1.1.1/24"]);
+    assert_eq!(netconf.args["cni"]["ips"][0], "10.1.1.2/24");
+    let config: RuntimeConfig = get_netconf("{}").unwrap().get_runtime_config().unwrap();
+    assert!(config.ips.is_empty());
+}
@@ -1,4 +1,5 @@
 pub mod types_020;
 pub mod types_040;
 pub mod types_100;
+pub mod types_110;
 pub mod types_common;
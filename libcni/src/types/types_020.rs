@@ -2,7 +2,7 @@
 use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 
-use super::types_100::*;
+use super::types_110::*;
 use super::types_common::Route;
 use super::types_common::DNS;
 use crate::ipnet;
@@ -35,21 +35,21 @@ pub struct CNI020Result {
 */
 impl CNI020Result {
     #[allow(unused)] // XXX to be removed
-    pub fn convert_to_latest(&self) -> CNI100Result {
-        CNI100Result {
-            cni_version: "1.0.0".to_string(),
+    pub fn convert_to_latest(&self) -> CNI110Result {
+        CNI110Result {
+            cni_version: "1.1.0".to_string(),
             interfaces: vec![],
             ips: vec![self.ip4.clone(), self.ip6.clone()]
                 .into_iter()
                 .filter_map(|x| match x {
-                    Some(y) => Some(CNI100IPAddress {
+                    Some(y) => Some(CNI110IPAddress {
                         interface: None,
                         address: y.address.clone(),
                         gateway: y.gateway,
                     }),
                     None => None,
                 })
-                .collect::<Vec<CNI100IPAddress>>(),
+                .collect::<Vec<CNI110IPAddress>>(),
             routes: vec![self.ip4.clone(), self.ip6.clone()]
                 .into_iter()
                 .filter_map(|x| match x {
@@ -57,13 +57,14 @@ impl CNI020Result {
                     None => None,
                 })
                 .flatten()
-                .collect::<Vec<Route>>(),
+                .map(|x| x.convert_to_latest())
+                .collect::<Vec<CNI110Route>>(),
             dns: self.dns.clone(),
         }
     }
 
     #[allow(unused)] // XXX to be removed
-    pub fn convert_from_latest(latest: &CNI100Result, cni_version: &str) -> CNI020Result {
+    pub fn convert_from_latest(latest: &CNI110Result, cni_version: &str) -> CNI020Result {
         let v4addr_opt = latest.ips.iter().fold(None, |v4addr, addr| {
             if v4addr.is_some() {
                 v4addr
@@ -87,7 +88,7 @@ impl CNI020Result {
             .iter()
             .fold(vec![], |mut v4_result: Vec<Route>, route| {
                 if route.dst.ip.is_ipv4() {
-                    v4_result.push(route.clone())
+                    v4_result.push(Route::convert_from_latest(route))
                 }
                 v4_result
             });
@@ -96,7 +97,7 @@ impl CNI020Result {
             .iter()
             .fold(vec![], |mut v6_result: Vec<Route>, route| {
                 if route.dst.ip.is_ipv6() {
-                    v6_result.push(route.clone())
+                    v6_result.push(Route::convert_from_latest(route))
                 }
                 v6_result
             });
@@ -2,7 +2,7 @@
 use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 
-use super::types_100::*;
+use super::types_110::*;
 use super::types_common::Route;
 use super::types_common::DNS;
 use crate::ipnet;
@@ -20,15 +20,18 @@ pub struct CNI040Interface {
 
 impl CNI040Interface {
     #[allow(unused)] // XXX to be removed
-    pub fn convert_to_latest(&self) -> CNI100Interface {
-        CNI100Interface {
+    pub fn convert_to_latest(&self) -> CNI110Interface {
+        CNI110Interface {
             name: self.name.clone(),
             mac: self.mac.clone(),
+            mtu: None,
             sandbox: self.sandbox.clone(),
+            socket_path: String::new(),
+            pci_id: String::new(),
         }
     }
     #[allow(unused)] // XXX to be removed
-    pub fn convert_from_latest(latest: &CNI100Interface) -> CNI040Interface {
+    pub fn convert_from_latest(latest: &CNI110Interface) -> CNI040Interface {
         CNI040Interface {
             name: latest.name.clone(),
             mac: latest.mac.clone(),
@@ -52,15 +55,15 @@ pub struct CNI040IPAddress {
 
 impl CNI040IPAddress {
     #[allow(unused)] // XXX to be removed
-    pub fn convert_to_latest(&self) -> CNI100IPAddress {
-        CNI100IPAddress {
+    pub fn convert_to_latest(&self) -> CNI110IPAddress {
+        CNI110IPAddress {
             interface: self.interface,
             address: self.address.clone(),
             gateway: self.gateway,
         }
     }
     #[allow(unused)] // XXX to be removed
-    pub fn convert_from_latest(latest: &CNI100IPAddress) -> CNI040IPAddress {
+    pub fn convert_from_latest(latest: &CNI110IPAddress) -> CNI040IPAddress {
         CNI040IPAddress {
             version: match latest.address.ip {
                 std::net::IpAddr::V4(_) => "4".to_string(),
@@ -90,21 +93,21 @@ pub struct CNI040Result {
 
 impl CNI040Result {
     #[allow(unused)] // XXX to be removed
-    pub fn convert_to_latest(&self) -> CNI100Result {
-        CNI100Result {
-            cni_version: "1.0.0".to_string(),
+    pub fn convert_to_latest(&self) -> CNI110Result {
+        CNI110Result {
+            cni_version: "1.1.0".to_string(),
             interfaces: self
                 .interfaces
                 .iter()
                 .map(|x| x.convert_to_latest())
                 .collect(),
             ips: self.ips.iter().map(|x| x.convert_to_latest()).collect(),
-            routes: self.routes.clone(),
+            routes: self.routes.iter().map(|x| x.convert_to_latest()).collect(),
             dns: self.dns.clone(),
         }
     }
     #[allow(unused)] // XXX to be removed
-    pub fn convert_from_latest(latest: &CNI100Result, cni_version: &str) -> CNI040Result {
+    pub fn convert_from_latest(latest: &CNI110Result, cni_version: &str) -> CNI040Result {
         CNI040Result {
             cni_version: cni_version.to_string(),
             interfaces: latest
@@ -117,7 +120,11 @@ impl CNI040Result {
                 .iter()
                 .map(CNI040IPAddress::convert_from_latest)
                 .collect(),
-            routes: latest.routes.clone(),
+            routes: latest
+                .routes
+                .iter()
+                .map(Route::convert_from_latest)
+                .collect(),
             dns: latest.dns.clone(),
         }
     }
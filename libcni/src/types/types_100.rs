@@ -2,6 +2,7 @@
 use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 
+use super::types_110::*;
 use super::types_common::*;
 use crate::ipnet;
 
@@ -41,3 +42,77 @@ pub struct CNI100Result {
     #[serde(rename = "dns", default)]
     pub dns: DNS,
 }
+
+impl CNI100Interface {
+    pub fn convert_to_latest(&self) -> CNI110Interface {
+        CNI110Interface {
+            name: self.name.clone(),
+            mac: self.mac.clone(),
+            mtu: None,
+            sandbox: self.sandbox.clone(),
+            socket_path: String::new(),
+            pci_id: String::new(),
+        }
+    }
+    pub fn convert_from_latest(latest: &CNI110Interface) -> CNI100Interface {
+        CNI100Interface {
+            name: latest.name.clone(),
+            mac: latest.mac.clone(),
+            sandbox: latest.sandbox.clone(),
+        }
+    }
+}
+
+impl CNI100IPAddress {
+    pub fn convert_to_latest(&self) -> CNI110IPAddress {
+        CNI110IPAddress {
+            interface: self.interface,
+            address: self.address.clone(),
+            gateway: self.gateway,
+        }
+    }
+    pub fn convert_from_latest(latest: &CNI110IPAddress) -> CNI100IPAddress {
+        CNI100IPAddress {
+            interface: latest.interface,
+            address: latest.address.clone(),
+            gateway: latest.gateway,
+        }
+    }
+}
+
+impl CNI100Result {
+    pub fn convert_to_latest(&self) -> CNI110Result {
+        CNI110Result {
+            cni_version: "1.1.0".to_string(),
+            interfaces: self
+                .interfaces
+                .iter()
+                .map(|x| x.convert_to_latest())
+                .collect(),
+            ips: self.ips.iter().map(|x| x.convert_to_latest()).collect(),
+            routes: self.routes.iter().map(|x| x.convert_to_latest()).collect(),
+            dns: self.dns.clone(),
+        }
+    }
+    pub fn convert_from_latest(latest: &CNI110Result, cni_version: &str) -> CNI100Result {
+        CNI100Result {
+            cni_version: cni_version.to_string(),
+            interfaces: latest
+                .interfaces
+                .iter()
+                .map(CNI100Interface::convert_from_latest)
+                .collect(),
+            ips: latest
+                .ips
+                .iter()
+                .map(CNI100IPAddress::convert_from_latest)
+                .collect(),
+            routes: latest
+                .routes
+                .iter()
+                .map(Route::convert_from_latest)
+                .collect(),
+            dns: latest.dns.clone(),
+        }
+    }
+}
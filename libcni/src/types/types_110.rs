@@ -0,0 +1,164 @@
+// types_110
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use thiserror::Error;
+
+use super::types_common::*;
+use crate::ipnet;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CNI110Interface {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "mac", default)]
+    pub mac: String,
+    #[serde(rename = "mtu", default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+    #[serde(rename = "sandbox", default)]
+    pub sandbox: String,
+    #[serde(
+        rename = "socketPath",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub socket_path: String,
+    #[serde(rename = "pciID", default, skip_serializing_if = "String::is_empty")]
+    pub pci_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CNI110IPAddress {
+    #[serde(rename = "interface", default)]
+    pub interface: Option<u8>,
+    #[serde(rename = "address")]
+    pub address: ipnet::IPNet,
+    #[serde(rename = "gateway", default)]
+    pub gateway: Option<IpAddr>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CNI110Route {
+    #[serde(rename = "dst")]
+    pub dst: ipnet::IPNet,
+    #[serde(rename = "gw", default, skip_serializing_if = "Option::is_none")]
+    pub gw: Option<IpAddr>,
+    #[serde(rename = "mtu", default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+    #[serde(rename = "advmss", default, skip_serializing_if = "Option::is_none")]
+    pub advmss: Option<u32>,
+    #[serde(rename = "priority", default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u32>,
+    #[serde(rename = "table", default, skip_serializing_if = "Option::is_none")]
+    pub table: Option<u32>,
+    #[serde(rename = "scope", default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CNI110Result {
+    #[serde(rename = "cniVersion", default)]
+    pub cni_version: String,
+    #[serde(rename = "interfaces", default, skip_serializing_if = "Vec::is_empty")]
+    pub interfaces: Vec<CNI110Interface>,
+    #[serde(rename = "ips", default, skip_serializing_if = "Vec::is_empty")]
+    pub ips: Vec<CNI110IPAddress>,
+    #[serde(rename = "routes", default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<CNI110Route>,
+    #[serde(rename = "dns", default)]
+    pub dns: DNS,
+}
+
+#[derive(Debug, Error)]
+pub enum ResultValidationError {
+    #[error("ips[{0}]: interface index {1} is out of range ({2} interfaces)")]
+    InvalidInterface(usize, u8, usize),
+}
+
+impl CNI110Result {
+    // index of the interface 'ifname' in the sandbox 'netns'. Interface in any
+    // sandbox is used if no sandbox path matches (runtimes may report other paths).
+    pub fn get_interface_index(&self, ifname: &str, netns: &str) -> Option<u8> {
+        let index = self
+            .interfaces
+            .iter()
+            .position(|i| i.name == ifname && i.sandbox == netns)
+            .or_else(|| {
+                self.interfaces
+                    .iter()
+                    .position(|i| i.name == ifname && !i.sandbox.is_empty())
+            })?;
+        u8::try_from(index).ok()
+    }
+
+    // check that 'interface' of each ip points to an entry of 'interfaces'
+    pub fn validate(&self) -> Result<(), ResultValidationError> {
+        for (i, ip) in self.ips.iter().enumerate() {
+            if let Some(index) = ip.interface {
+                if index as usize >= self.interfaces.len() {
+                    return Err(ResultValidationError::InvalidInterface(
+                        i,
+                        index,
+                        self.interfaces.len(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_interface_index() {
+    let mut result: CNI110Result = serde_json::from_str(
+        r#"{
+            "cniVersion": "1.1.0",
+            "interfaces": [
+                { "name": "net1", "mac": "00:11:22:33:44:55" },
+                { "name": "net1", "sandbox": "/var/run/netns/test" }
+            ],
+            "ips": [{ "interface": 1, "address": "10.1.1.1/24" }]
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        result.get_interface_index("net1", "/var/run/netns/test"),
+        Some(1)
+    );
+    assert_eq!(
+        result.get_interface_index("net1", "/proc/1/ns/net"),
+        Some(1)
+    );
+    assert_eq!(
+        result.get_interface_index("eth0", "/var/run/netns/test"),
+        None
+    );
+    assert!(result.validate().is_ok());
+
+    result.ips[0].interface = Some(2);
+    assert!(result.validate().is_err());
+}
+
+#[test]
+fn test_convert_100() {
+    use super::types_100::CNI100Result;
+
+    let result: CNI110Result = serde_json::from_str(
+        r#"{
+            "cniVersion": "1.1.0",
+            "interfaces": [{ "name": "net1", "mtu": 9000, "pciID": "0000:00:1f.6" }],
+            "ips": [{ "interface": 0, "address": "10.1.1.1/24" }],
+            "routes": [{ "dst": "0.0.0.0/0", "gw": "10.1.1.254", "table": 100 }]
+        }"#,
+    )
+    .unwrap();
+    let old = CNI100Result::convert_from_latest(&result, "1.0.0");
+    let output = serde_json::to_value(&old).unwrap();
+    assert!(output["interfaces"][0].get("pciID").is_none());
+    assert!(output["routes"][0].get("table").is_none());
+    assert_eq!(output["routes"][0]["gw"], "10.1.1.254");
+
+    let latest = old.convert_to_latest();
+    assert_eq!(latest.cni_version, "1.1.0");
+    assert_eq!(latest.ips[0].interface, Some(0));
+    assert_eq!(latest.routes[0].table, None);
+}
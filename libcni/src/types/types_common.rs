@@ -1,4 +1,5 @@
 // types
+use super::types_110::CNI110Route;
 use crate::ipnet;
 use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
@@ -8,6 +9,7 @@ use std::net::IpAddr;
  * each version's result, except latest version, have conversion function to
  * latest and vice versa. For example, types040 structure (for cniVersion
  * 0.3.0/0.3.1/0.4.0) has 'convert_to_latest()' and 'convert_from_latest()'.
+ * Latest version is types110 (cniVersion 1.1.0).
  *
  * In usual cni plugin, once read previous CNI result and convert to latest,
  * then modify (e.g. add interface, ip address), at last return to the
@@ -32,6 +34,76 @@ pub struct DNS {
 pub struct Route {
     #[serde(rename = "dst")]
     pub dst: ipnet::IPNet,
-    #[serde(rename = "gw")]
-    pub gw: IpAddr,
+    #[serde(rename = "gw", default, skip_serializing_if = "Option::is_none")]
+    pub gw: Option<IpAddr>,
 }
+
+impl Route {
+    pub fn convert_to_latest(&self) -> CNI110Route {
+        CNI110Route {
+            dst: self.dst.clone(),
+            gw: self.gw,
+            mtu: None,
+            advmss: None,
+            priority: None,
+            table: None,
+            scope: None,
+        }
+    }
+    pub fn convert_from_latest(latest: &CNI110Route) -> Route {
+        Route {
+            dst: latest.dst.clone(),
+            gw: latest.gw,
+        }
+    }
+}
+
+// well-known error codes, see CNI spec 'Error'
+pub const ERR_INCOMPATIBLE_CNI_VERSION: u32 = 1;
+pub const ERR_UNSUPPORTED_FIELD: u32 = 2;
+pub const ERR_UNKNOWN_CONTAINER: u32 = 3;
+pub const ERR_INVALID_ENVIRONMENT_VARIABLES: u32 = 4;
+pub const ERR_IO_FAILURE: u32 = 5;
+pub const ERR_DECODING_FAILURE: u32 = 6;
+pub const ERR_INVALID_NETWORK_CONFIG: u32 = 7;
+pub const ERR_TRY_AGAIN_LATER: u32 = 11;
+// STATUS only
+pub const ERR_PLUGIN_NOT_AVAILABLE: u32 = 50;
+pub const ERR_PLUGIN_NOT_AVAILABLE_LIMITED_CONNECTIVITY: u32 = 51;
+pub const ERR_INTERNAL: u32 = 999;
+
+// error returned to the runtime (printed to stdout)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CNIError {
+    #[serde(rename = "cniVersion", default)]
+    pub cni_version: String,
+    #[serde(rename = "code")]
+    pub code: u32,
+    #[serde(rename = "msg", default)]
+    pub msg: String,
+    #[serde(rename = "details", default, skip_serializing_if = "String::is_empty")]
+    pub details: String,
+}
+
+impl CNIError {
+    pub fn new(code: u32, msg: &str, details: &str) -> CNIError {
+        CNIError {
+            cni_version: String::new(),
+            code,
+            msg: msg.to_string(),
+            details: details.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for CNIError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.details.is_empty() {
+            write!(f, "{}", self.msg)
+        } else {
+            write!(f, "{}; {}", self.msg, self.details)
+        }
+    }
+}
+
+impl std::error::Error for CNIError {}
@@ -0,0 +1,156 @@
+// version.rs: plugin version info and cniVersion compatibility check
+use crate::skel::NetConf;
+use crate::types::types_common::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// CNI versions which results can be converted to/from
+pub const SUPPORTED_VERSIONS: &[&str] = &[
+    "0.1.0", "0.2.0", "0.3.0", "0.3.1", "0.4.0", "1.0.0", "1.1.0",
+];
+
+// version used when cniVersion is omitted, same as golang CNI
+pub const DEFAULT_VERSION: &str = "0.1.0";
+
+// output of VERSION command
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PluginInfo {
+    #[serde(rename = "cniVersion", default)]
+    pub cni_version: String,
+    #[serde(rename = "supportedVersions", default)]
+    pub supported_versions: Vec<String>,
+}
+
+impl PluginInfo {
+    pub fn new(supported_versions: &[&str]) -> PluginInfo {
+        PluginInfo {
+            cni_version: supported_versions.last().unwrap_or(&"").to_string(),
+            supported_versions: supported_versions.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+
+    // all versions libcni supports
+    pub fn all() -> PluginInfo {
+        PluginInfo::new(SUPPORTED_VERSIONS)
+    }
+
+    pub fn supports(&self, version: &str) -> bool {
+        self.supported_versions.iter().any(|v| v == version)
+    }
+
+    // VERSION command: stdin is '{"cniVersion": "x.y.z"}' which the runtime uses
+    pub fn get_version_output(&self, stdin: &str) -> Result<String, CNIError> {
+        let mut info = self.clone();
+        if !stdin.trim().is_empty() {
+            let conf: Value = serde_json::from_str(stdin).map_err(|e| {
+                CNIError::new(
+                    ERR_DECODING_FAILURE,
+                    "failed to parse stdin",
+                    &e.to_string(),
+                )
+            })?;
+            if let Some(version) = conf["cniVersion"].as_str() {
+                if self.supports(version) {
+                    info.cni_version = version.to_string();
+                }
+            }
+        }
+        serde_json::to_string(&info)
+            .map_err(|e| CNIError::new(ERR_INTERNAL, "failed to encode json", &e.to_string()))
+    }
+
+    // check that both netconf and prevResult are in supported versions, and
+    // the command is available in the version
+    pub fn check_compatible(&self, command: &str, netconf: &NetConf) -> Result<(), CNIError> {
+        let config_version = match netconf.cni_version.as_str() {
+            "" => DEFAULT_VERSION,
+            v => v,
+        };
+        let incompatible = |details: String| {
+            let mut err = CNIError::new(
+                ERR_INCOMPATIBLE_CNI_VERSION,
+                "incompatible CNI versions",
+                &details,
+            );
+            err.cni_version = config_version.to_string();
+            err
+        };
+        if !self.supports(config_version) {
+            return Err(incompatible(format!(
+                "config is \"{}\", plugin supports {:?}",
+                config_version, self.supported_versions
+            )));
+        }
+        if let Some(result_version) = netconf.prev_result["cniVersion"].as_str() {
+            if !self.supports(result_version) {
+                return Err(incompatible(format!(
+                    "prevResult is \"{}\", plugin supports {:?}",
+                    result_version, self.supported_versions
+                )));
+            }
+        }
+        let required = match command {
+            "CHECK" => "0.4.0",
+            "GC" | "STATUS" => "1.1.0",
+            _ => DEFAULT_VERSION,
+        };
+        if parse_version(config_version) < parse_version(required) {
+            return Err(incompatible(format!(
+                "{} requires config version >= {}, but config is \"{}\"",
+                command, required, config_version
+            )));
+        }
+        Ok(())
+    }
+}
+
+// "x.y.z" to (x, y, z), unknown part is 0
+pub fn parse_version(version: &str) -> (u32, u32, u32) {
+    let mut parts = version.split('.').map(|x| x.parse::<u32>().unwrap_or(0));
+    (
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+    )
+}
+
+#[test]
+fn test_version_output() {
+    let info = PluginInfo::new(&["0.4.0", "1.0.0", "1.1.0"]);
+    let output: Value = serde_json::from_str(&info.get_version_output("").unwrap()).unwrap();
+    assert_eq!(output["cniVersion"], "1.1.0");
+    assert_eq!(output["supportedVersions"][0], "0.4.0");
+
+    let output: Value = serde_json::from_str(
+        &info
+            .get_version_output(r#"{"cniVersion":"1.0.0"}"#)
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(output["cniVersion"], "1.0.0");
+}
+
+#[test]
+fn test_check_compatible() {
+    let info = PluginInfo::new(&["0.3.1", "0.4.0", "1.0.0"]);
+    let netconf = |s: &str| serde_json::from_str::<NetConf>(s).unwrap();
+
+    assert!(info
+        .check_compatible("ADD", &netconf(r#"{"cniVersion": "1.0.0"}"#))
+        .is_ok());
+    let err = info
+        .check_compatible("ADD", &netconf(r#"{"cniVersion": "1.1.0"}"#))
+        .unwrap_err();
+    assert_eq!(err.code, ERR_INCOMPATIBLE_CNI_VERSION);
+    let err = info
+        .check_compatible(
+            "DEL",
+            &netconf(r#"{"cniVersion": "1.0.0", "prevResult": {"cniVersion": "0.2.0"}}"#),
+        )
+        .unwrap_err();
+    assert_eq!(err.code, ERR_INCOMPATIBLE_CNI_VERSION);
+    assert!(info
+        .check_compatible("CHECK", &netconf(r#"{"cniVersion": "0.3.1"}"#))
+        .is_err());
+    assert!(info.check_compatible("ADD", &netconf("{}")).is_err());
+}
@@ -0,0 +1,234 @@
+// admin.rs: operator subcommands to inspect and manipulate allocations
+use std::net::IpAddr;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::kube_crd::{get_ipallocation_excludes, NetworkIP, NetworkIPAllocations};
+use crate::redisdb;
+
+// owner of addresses reserved by 'reserve' subcommand. It contains no '/', so
+// the controller does not take it as pod and does not reclaim it.
+pub const RESERVED_OWNER: &str = "Reserved";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(anyhow::anyhow!("unknown output format: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct AddressEntry {
+    pub allocation: String,
+    pub ip: IpAddr,
+    pub state: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub owner: String,
+}
+
+// render rows as left-aligned columns
+fn format_table(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (i, col) in row.iter().enumerate() {
+            widths[i] = widths[i].max(col.len());
+        }
+    }
+    let format_row = |row: Vec<&str>| {
+        row.iter()
+            .enumerate()
+            .map(|(i, col)| format!("{:<width$}", col, width = widths[i]))
+            .collect::<Vec<String>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    let mut lines = vec![format_row(header.to_vec())];
+    for row in rows {
+        lines.push(format_row(row.iter().map(|s| s.as_str()).collect()));
+    }
+    lines.join("\n")
+}
+
+#[test]
+fn test_format_table() {
+    let rows = vec![
+        vec!["testIPv4".to_string(), "10.1.1.100".to_string()],
+        vec!["v6".to_string(), "10::1:1".to_string()],
+    ];
+    assert_eq!(
+        format_table(&["ALLOCATION", "IP"], &rows),
+        "ALLOCATION  IP\ntestIPv4    10.1.1.100\nv6          10::1:1"
+    );
+}
+
+fn format_entries(entries: &[AddressEntry], format: OutputFormat) -> Result<String> {
+    Ok(match format {
+        OutputFormat::Json => serde_json::to_string_pretty(entries)?,
+        OutputFormat::Table => format_table(
+            &["ALLOCATION", "IP", "STATE", "OWNER"],
+            &entries
+                .iter()
+                .map(|e| {
+                    vec![
+                        e.allocation.clone(),
+                        e.ip.to_string(),
+                        e.state.clone(),
+                        e.owner.clone(),
+                    ]
+                })
+                .collect::<Vec<Vec<String>>>(),
+        ),
+    })
+}
+
+fn find_allocation<'a>(networkip: &'a NetworkIP, ip: &IpAddr) -> Result<&'a NetworkIPAllocations> {
+    networkip
+        .spec
+        .ip_allocations
+        .iter()
+        .find(|alloc| alloc.contains_ip(ip))
+        .ok_or_else(|| anyhow::anyhow!("{} is not in any allocation range", ip))
+}
+
+fn get_owner_state(owner: &str) -> &'static str {
+    if owner == RESERVED_OWNER {
+        "reserved"
+    } else {
+        "allocated"
+    }
+}
+
+// list addresses which have owner information
+pub fn list(con: &mut redis::Connection, networkip: &NetworkIP, format: OutputFormat) -> Result<String> {
+    let mut entries = vec![];
+    for alloc in networkip.spec.ip_allocations.iter() {
+        let mut pod_infos = redisdb::get_pod_informations(con, networkip, alloc)?;
+        pod_infos.sort();
+        for (ip, owner) in pod_infos {
+            entries.push(AddressEntry {
+                allocation: alloc.name.clone(),
+                ip,
+                state: get_owner_state(&owner).to_string(),
+                owner,
+            });
+        }
+    }
+    format_entries(&entries, format)
+}
+
+pub fn show(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    ip: &IpAddr,
+    format: OutputFormat,
+) -> Result<String> {
+    let alloc = find_allocation(networkip, ip)?;
+    let owner = redisdb::get_pod_information(con, networkip, alloc, ip)?.unwrap_or_default();
+    let state = if get_ipallocation_excludes(alloc).contains(ip) {
+        "excluded"
+    } else if !owner.is_empty() {
+        get_owner_state(&owner)
+    } else if redisdb::is_ip_used(con, networkip, alloc, ip)? {
+        // bit is set but nobody owns it
+        "orphaned"
+    } else {
+        "free"
+    };
+    format_entries(
+        &[AddressEntry {
+            allocation: alloc.name.clone(),
+            ip: *ip,
+            state: state.to_string(),
+            owner,
+        }],
+        format,
+    )
+}
+
+pub fn usage(con: &mut redis::Connection, networkip: &NetworkIP, format: OutputFormat) -> Result<String> {
+    let status = redisdb::get_network_status(con, networkip)?;
+    Ok(match format {
+        OutputFormat::Json => serde_json::to_string_pretty(&status)?,
+        OutputFormat::Table => format_table(
+            &["ALLOCATION", "CAPACITY", "ALLOCATED", "FREE", "EXCLUDED"],
+            &status
+                .allocations
+                .iter()
+                .map(|a| {
+                    vec![
+                        a.name.clone(),
+                        a.capacity.to_string(),
+                        a.allocated.to_string(),
+                        a.free.to_string(),
+                        a.excluded.to_string(),
+                    ]
+                })
+                .collect::<Vec<Vec<String>>>(),
+        ),
+    })
+}
+
+// table format prints what is done, json prints the address entry after the change
+pub fn reserve(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    ip: &IpAddr,
+    format: OutputFormat,
+) -> Result<String> {
+    let alloc = find_allocation(networkip, ip)?;
+    if !redisdb::reserve_ip(con, networkip, alloc, ip)? {
+        return Err(anyhow::anyhow!("{} is already in use", ip));
+    }
+    redisdb::add_pod_information(con, networkip, alloc, ip, RESERVED_OWNER.to_string())?;
+    match format {
+        OutputFormat::Json => format_entries(
+            &[AddressEntry {
+                allocation: alloc.name.clone(),
+                ip: *ip,
+                state: get_owner_state(RESERVED_OWNER).to_string(),
+                owner: RESERVED_OWNER.to_string(),
+            }],
+            format,
+        ),
+        OutputFormat::Table => Ok(format!("{} reserved in {}", ip, alloc.name)),
+    }
+}
+
+pub fn release(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    ip: &IpAddr,
+    format: OutputFormat,
+) -> Result<String> {
+    let alloc = find_allocation(networkip, ip)?;
+    if get_ipallocation_excludes(alloc).contains(ip) {
+        return Err(anyhow::anyhow!("{} is excluded and cannot be released", ip));
+    }
+    redisdb::del_pod_information(con, networkip, alloc, ip)?;
+    redisdb::return_ip(con, networkip, alloc, *ip)?;
+    match format {
+        OutputFormat::Json => format_entries(
+            &[AddressEntry {
+                allocation: alloc.name.clone(),
+                ip: *ip,
+                state: "free".to_string(),
+                owner: String::new(),
+            }],
+            format,
+        ),
+        OutputFormat::Table => Ok(format!("{} released in {}", ip, alloc.name)),
+    }
+}
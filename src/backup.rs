@@ -0,0 +1,375 @@
+// backup.rs: export/import of allocation state for backup and restore
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use anyhow::Result;
+use kube::api::ObjectMeta;
+use serde::{Deserialize, Serialize};
+
+use crate::kube_crd::{NetworkIP, NetworkIPSpec};
+use crate::redisdb;
+
+pub const DUMP_API_VERSION: &str = "differance.io/v1";
+pub const DUMP_KIND: &str = "AllocationDump";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    Yaml,
+    Json,
+}
+
+impl std::str::FromStr for DumpFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "yaml" => Ok(DumpFormat::Yaml),
+            "json" => Ok(DumpFormat::Json),
+            _ => Err(anyhow::anyhow!("unknown dump format: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AllocationState {
+    /// name specifies identifier of the allocations
+    pub name: String,
+    /// baseip specifies the address of bit 0 in the bitmap (absent if not initialized)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baseip: Option<String>,
+    /// bitmap specifies base64 encoded redis bitmap
+    #[serde(default)]
+    pub bitmap: String,
+    /// blocks specifies the node which claimed each block (block affinity mode)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub blocks: redisdb::BlockOwners,
+    /// owners specifies pod information of each address
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub owners: BTreeMap<IpAddr, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NetworkState {
+    pub namespace: String,
+    pub name: String,
+    pub spec: NetworkIPSpec,
+    pub allocations: Vec<AllocationState>,
+}
+
+// networkips of the attachment, which DEL uses when the pod annotation is gone
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ContainerState {
+    #[serde(rename = "containerID")]
+    pub container_id: String,
+    pub ifname: String,
+    #[serde(rename = "netconfName")]
+    pub netconf_name: String,
+    pub network: String,
+}
+
+impl ContainerState {
+    fn key(&self) -> String {
+        format!("{}/{}/{}", self.container_id, self.ifname, self.netconf_name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AllocationDump {
+    #[serde(rename = "apiVersion")]
+    pub api_version: String,
+    pub kind: String,
+    pub networks: Vec<NetworkState>,
+    /// containers specifies attachments which use the networks
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub containers: Vec<ContainerState>,
+}
+
+impl NetworkState {
+    // NetworkIP object which has enough information to build redis keys
+    fn to_networkip(&self) -> NetworkIP {
+        NetworkIP {
+            metadata: ObjectMeta {
+                namespace: Some(self.namespace.clone()),
+                name: Some(self.name.clone()),
+                ..Default::default()
+            },
+            spec: self.spec.clone(),
+            status: None,
+        }
+    }
+}
+
+fn count_bits(bitmap: &[u8]) -> u32 {
+    bitmap.iter().map(|b| b.count_ones()).sum()
+}
+
+fn get_network_state(con: &mut redis::Connection, networkip: &NetworkIP) -> Result<NetworkState> {
+    let mut allocations = vec![];
+    for alloc in networkip.spec.ip_allocations.iter() {
+        let (baseip, bitmap, blocks) = redisdb::get_allocation_state(con, networkip, alloc)?;
+        allocations.push(AllocationState {
+            name: alloc.name.clone(),
+            baseip,
+            bitmap: base64::encode(bitmap),
+            blocks,
+            owners: redisdb::get_pod_informations(con, networkip, alloc)?
+                .into_iter()
+                .collect(),
+        });
+    }
+    Ok(NetworkState {
+        namespace: networkip.metadata.namespace.clone().unwrap_or_default(),
+        name: networkip.metadata.name.clone().unwrap_or_default(),
+        spec: networkip.spec.clone(),
+        allocations,
+    })
+}
+
+// container records which refer to any of 'networks' ('<namespace>/<name>')
+fn get_container_states(con: &mut redis::Connection, networks: &[String]) -> Result<Vec<ContainerState>> {
+    Ok(redisdb::get_container_networks(con)?
+        .into_iter()
+        .filter(|c| c.network.split(',').any(|n| networks.iter().any(|m| m == n.trim())))
+        .map(|c| ContainerState {
+            container_id: c.container_id,
+            ifname: c.ifname,
+            netconf_name: c.netconf_name,
+            network: c.network,
+        })
+        .collect())
+}
+
+pub fn dump(con: &mut redis::Connection, networkips: &[NetworkIP]) -> Result<AllocationDump> {
+    let networks = networkips
+        .iter()
+        .map(|networkip| get_network_state(con, networkip))
+        .collect::<Result<Vec<NetworkState>>>()?;
+    let names: Vec<String> = networks.iter().map(|n| format!("{}/{}", n.namespace, n.name)).collect();
+    Ok(AllocationDump {
+        api_version: DUMP_API_VERSION.to_string(),
+        kind: DUMP_KIND.to_string(),
+        networks,
+        containers: get_container_states(con, &names)?,
+    })
+}
+
+pub fn format_dump(dump: &AllocationDump, format: DumpFormat) -> Result<String> {
+    Ok(match format {
+        DumpFormat::Yaml => serde_yaml::to_string(dump)?,
+        DumpFormat::Json => serde_json::to_string_pretty(dump)?,
+    })
+}
+
+// parse dump document in YAML or JSON (JSON is a subset of YAML)
+pub fn parse_dump(document: &str) -> Result<AllocationDump> {
+    let dump: AllocationDump = serde_yaml::from_str(document)?;
+    if dump.api_version != DUMP_API_VERSION || dump.kind != DUMP_KIND {
+        return Err(anyhow::anyhow!(
+            "unsupported dump: {} {} (expected {} {})",
+            dump.api_version,
+            dump.kind,
+            DUMP_API_VERSION,
+            DUMP_KIND
+        ));
+    }
+    Ok(dump)
+}
+
+fn diff_allocation(key: &str, current: &AllocationState, target: &AllocationState) -> Result<Vec<String>> {
+    let mut lines = vec![];
+    match (&current.baseip, &target.baseip) {
+        (None, Some(t)) => lines.push(format!("+ {} baseip {}", key, t)),
+        (Some(c), Some(t)) if c != t => lines.push(format!("~ {} baseip {} -> {}", key, c, t)),
+        (Some(c), None) => lines.push(format!("- {} baseip {}", key, c)),
+        _ => {}
+    }
+    let current_bits = count_bits(&base64::decode(&current.bitmap)?);
+    let target_bits = count_bits(&base64::decode(&target.bitmap)?);
+    if current.bitmap != target.bitmap {
+        lines.push(format!(
+            "~ {} bitmap {} -> {} addresses used",
+            key, current_bits, target_bits
+        ));
+    }
+    for (block, node) in target.blocks.iter() {
+        match current.blocks.get(block) {
+            None => lines.push(format!("+ {} block {} {}", key, block, node)),
+            Some(c) if c != node => lines.push(format!("~ {} block {} {} -> {}", key, block, c, node)),
+            _ => {}
+        }
+    }
+    for (ip, owner) in target.owners.iter() {
+        match current.owners.get(ip) {
+            None => lines.push(format!("+ {} {} {}", key, ip, owner)),
+            Some(c) if c != owner => lines.push(format!("~ {} {} {} -> {}", key, ip, c, owner)),
+            _ => {}
+        }
+    }
+    for (ip, owner) in current.owners.iter() {
+        if !target.owners.contains_key(ip) {
+            lines.push(format!("- {} {} {}", key, ip, owner));
+        }
+    }
+    Ok(lines)
+}
+
+#[test]
+fn test_diff_allocation() {
+    let current = AllocationState {
+        name: "testIPv4".to_string(),
+        baseip: None,
+        bitmap: "".to_string(),
+        blocks: BTreeMap::new(),
+        owners: BTreeMap::new(),
+    };
+    let target = AllocationState {
+        name: "testIPv4".to_string(),
+        baseip: Some("10.1.1.100".to_string()),
+        bitmap: base64::encode([0xc0u8]),
+        blocks: [("0".to_string(), "node1".to_string())].into_iter().collect(),
+        owners: [("10.1.1.101".parse().unwrap(), "default/pod1 abcd".to_string())]
+            .into_iter()
+            .collect(),
+    };
+    assert_eq!(
+        diff_allocation("default/net/testIPv4", &current, &target).unwrap(),
+        vec![
+            "+ default/net/testIPv4 baseip 10.1.1.100",
+            "~ default/net/testIPv4 bitmap 0 -> 2 addresses used",
+            "+ default/net/testIPv4 block 0 node1",
+            "+ default/net/testIPv4 10.1.1.101 default/pod1 abcd",
+        ]
+    );
+    assert!(diff_allocation("default/net/testIPv4", &target, &target)
+        .unwrap()
+        .is_empty());
+}
+
+// returns the changes restore() would make. 'conflicts' lists allocations and
+// container records which already have state in redis; restore refuses to
+// overwrite them.
+pub fn diff(con: &mut redis::Connection, dump: &AllocationDump) -> Result<(Vec<String>, Vec<String>)> {
+    let mut lines = vec![];
+    let mut conflicts = vec![];
+    for network in dump.networks.iter() {
+        let current = get_network_state(con, &network.to_networkip())?;
+        for target in network.allocations.iter() {
+            let key = format!("{}/{}/{}", network.namespace, network.name, target.name);
+            let current = current
+                .allocations
+                .iter()
+                .find(|a| a.name == target.name)
+                .ok_or_else(|| anyhow::anyhow!("allocation {} is not in spec", key))?;
+            if current.baseip.is_some() || !current.blocks.is_empty() || !current.owners.is_empty() {
+                conflicts.push(key.clone());
+            }
+            lines.extend(diff_allocation(&key, current, target)?);
+        }
+    }
+    for target in dump.containers.iter() {
+        let key = format!("container {}", target.key());
+        match redisdb::get_container_network(con, &target.container_id, &target.ifname, &target.netconf_name)? {
+            None => lines.push(format!("+ {} {}", key, target.network)),
+            Some(c) if c != target.network => {
+                lines.push(format!("~ {} {} -> {}", key, c, target.network));
+                conflicts.push(key);
+            }
+            _ => {}
+        }
+    }
+    Ok((lines, conflicts))
+}
+
+// load the dump into redis. Every allocation in the dump must be empty in redis.
+pub fn restore(con: &mut redis::Connection, dump: &AllocationDump) -> Result<()> {
+    let (_, conflicts) = diff(con, dump)?;
+    if !conflicts.is_empty() {
+        return Err(anyhow::anyhow!(
+            "allocation state already exists: {}",
+            conflicts.join(", ")
+        ));
+    }
+    for network in dump.networks.iter() {
+        let networkip = network.to_networkip();
+        redisdb::set_network_spec(con, &networkip)?;
+        for state in network.allocations.iter() {
+            let baseip = match &state.baseip {
+                Some(v) => v,
+                // not initialized at dump time
+                None => continue,
+            };
+            let alloc = networkip
+                .spec
+                .ip_allocations
+                .iter()
+                .find(|a| a.name == state.name)
+                .ok_or_else(|| anyhow::anyhow!("allocation {} is not in spec", state.name))?;
+            let owners: Vec<(IpAddr, String)> = state
+                .owners
+                .iter()
+                .map(|(ip, owner)| (*ip, owner.clone()))
+                .collect();
+            redisdb::set_allocation_state(
+                con,
+                &networkip,
+                alloc,
+                baseip,
+                &base64::decode(&state.bitmap)?,
+                &state.blocks,
+                &owners,
+            )?;
+        }
+    }
+    for container in dump.containers.iter() {
+        redisdb::set_container_network(
+            con,
+            &container.container_id,
+            &container.ifname,
+            &container.netconf_name,
+            &container.network,
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_parse_dump() {
+    let document = r#"
+apiVersion: differance.io/v1
+kind: AllocationDump
+networks:
+  - namespace: default
+    name: test-network-ipv4
+    spec:
+      ipAllocations:
+        - name: testIPv4
+          subnet: 10.1.1.0/24
+    allocations:
+      - name: testIPv4
+        baseip: 10.1.1.1
+        bitmap: gA==
+        blocks:
+          "0": node1
+        owners:
+          10.1.1.1: default/pod1 abcd
+containers:
+  - containerID: abcd
+    ifname: net1
+    netconfName: macvlan
+    network: default/test-network-ipv4
+"#;
+    let dump = parse_dump(document).unwrap();
+    assert_eq!(dump.networks[0].allocations[0].owners.len(), 1);
+    assert_eq!(dump.containers[0].key(), "abcd/net1/macvlan");
+    // both output formats can be read again
+    for format in ["yaml", "json"] {
+        let output = format_dump(&dump, format.parse().unwrap()).unwrap();
+        let parsed = parse_dump(&output).unwrap();
+        assert_eq!(parsed.networks[0].name, "test-network-ipv4");
+        assert_eq!(parsed.networks[0].allocations[0].blocks, dump.networks[0].allocations[0].blocks);
+        assert_eq!(parsed.containers, dump.containers);
+    }
+    assert!("table".parse::<DumpFormat>().is_err());
+
+    assert!(parse_dump(&document.replace("differance.io/v1", "differance.io/v0")).is_err());
+}
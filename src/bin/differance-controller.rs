@@ -0,0 +1,89 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::{App, Arg};
+use differance::{controller, kube_crd, logging, metrics};
+use kube::Client;
+use redis::Client as RedisClient;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let kubeconfig_arg: Arg = Arg::new("kubeconfig")
+        .help("kubeconfig path (in-cluster config is used if omitted)")
+        .long("kubeconfig")
+        .takes_value(true)
+        .required(false);
+    let redis_arg: Arg = Arg::new("redis")
+        .help("redis URL, e.g. redis://10.1.1.1/")
+        .long("redis")
+        .takes_value(true)
+        .required(true);
+    let interval_arg: Arg = Arg::new("interval")
+        .help("resync interval in seconds")
+        .long("interval")
+        .takes_value(true)
+        .default_value("60");
+    let metrics_arg: Arg = Arg::new("metrics-listen")
+        .help("listen address of prometheus metrics endpoint, e.g. 0.0.0.0:9090")
+        .long("metrics-listen")
+        .takes_value(true)
+        .required(false);
+    let log_level_arg: Arg = Arg::new("log-level")
+        .help("log level (off, error, warn, info, debug, trace)")
+        .long("log-level")
+        .takes_value(true)
+        .default_value("info");
+    let log_format_arg: Arg = Arg::new("log-format")
+        .help("log format")
+        .long("log-format")
+        .takes_value(true)
+        .value_parser(["text", "json"])
+        .default_value("text");
+
+    let app: App = App::new("differance-controller")
+        .author("Tomofumi Hayashi")
+        .version("0.1.0")
+        .about("NetworkIP pool controller for differance-cni")
+        .arg(kubeconfig_arg)
+        .arg(redis_arg)
+        .arg(interval_arg)
+        .arg(metrics_arg)
+        .arg(log_level_arg)
+        .arg(log_format_arg);
+    let matches = app.try_get_matches()?;
+
+    logging::init(
+        matches.get_one::<String>("log-level").unwrap(),
+        matches.get_one::<String>("log-format").unwrap().parse()?,
+        None,
+    )?;
+
+    let client = match matches.get_one::<String>("kubeconfig") {
+        Some(s) => kube_crd::get_client_kubeconfig(s).await?,
+        None => Client::try_default().await?,
+    };
+    if !(kube_crd::check_crd(&client).await) {
+        return Err(anyhow::anyhow!("no CRD {} found", kube_crd::CRD_NAME));
+    };
+    let redis_client = RedisClient::open(matches.get_one::<String>("redis").unwrap().as_str())?;
+    let interval: u64 = matches.get_one::<String>("interval").unwrap().parse()?;
+
+    if let Some(addr) = matches.get_one::<String>("metrics-listen") {
+        let addr: SocketAddr = addr.parse()?;
+        let (client, redis_client) = (client.clone(), redis_client.clone());
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr, client, redis_client).await {
+                tracing::error!("metrics server failed: {}", e);
+            }
+        });
+    }
+
+    controller::run(controller::Context {
+        client,
+        redis_client,
+        interval: Duration::from_secs(interval),
+    })
+    .await;
+    Ok(())
+}
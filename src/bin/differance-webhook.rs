@@ -0,0 +1,60 @@
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use clap::{App, Arg};
+use differance::{logging, webhook};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let listen_arg: Arg = Arg::new("listen")
+        .help("listen address")
+        .long("listen")
+        .takes_value(true)
+        .default_value("0.0.0.0:8443");
+    let tls_cert_arg: Arg = Arg::new("tls-cert")
+        .help("TLS certificate (PEM) path")
+        .long("tls-cert")
+        .takes_value(true)
+        .required(true);
+    let tls_key_arg: Arg = Arg::new("tls-key")
+        .help("TLS private key (PEM) path")
+        .long("tls-key")
+        .takes_value(true)
+        .required(true);
+    let log_level_arg: Arg = Arg::new("log-level")
+        .help("log level (off, error, warn, info, debug, trace)")
+        .long("log-level")
+        .takes_value(true)
+        .default_value("info");
+    let log_format_arg: Arg = Arg::new("log-format")
+        .help("log format")
+        .long("log-format")
+        .takes_value(true)
+        .value_parser(["text", "json"])
+        .default_value("text");
+
+    let app: App = App::new("differance-webhook")
+        .author("Tomofumi Hayashi")
+        .version("0.1.0")
+        .about("validating admission webhook for NetworkIP")
+        .arg(listen_arg)
+        .arg(tls_cert_arg)
+        .arg(tls_key_arg)
+        .arg(log_level_arg)
+        .arg(log_format_arg);
+    let matches = app.try_get_matches()?;
+
+    logging::init(
+        matches.get_one::<String>("log-level").unwrap(),
+        matches.get_one::<String>("log-format").unwrap().parse()?,
+        None,
+    )?;
+
+    let addr: SocketAddr = matches.get_one::<String>("listen").unwrap().parse()?;
+    webhook::serve(
+        addr,
+        matches.get_one::<String>("tls-cert").unwrap(),
+        matches.get_one::<String>("tls-key").unwrap(),
+    )
+    .await
+}
@@ -0,0 +1,251 @@
+// controller.rs: reconciles NetworkIP pools in redis and reclaims leaked addresses
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::{Api, ListParams},
+    runtime::controller::{Action, Controller},
+    runtime::reflector::ObjectRef,
+    Client,
+};
+use redis::Client as RedisClient;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::kube_crd::{self, NetworkIP, NetworkIPStatus};
+use crate::redisdb::{self, PodInformation};
+use crate::validation;
+
+pub struct Context {
+    pub client: Client,
+    pub redis_client: RedisClient,
+    /// interval specifies how often each NetworkIP is reconciled without any event
+    pub interval: Duration,
+}
+
+#[derive(Debug, Error)]
+pub enum ReconcileError {
+    #[error("kubernetes api error: {0}")]
+    KubeError(kube::Error),
+    #[error("redis error: {0}")]
+    RedisError(redis::RedisError),
+    #[error("failed to reconcile: {0}")]
+    FailedAnyhow(anyhow::Error),
+}
+
+impl From<kube::Error> for ReconcileError {
+    fn from(err: kube::Error) -> ReconcileError {
+        ReconcileError::KubeError(err)
+    }
+}
+
+impl From<redis::RedisError> for ReconcileError {
+    fn from(err: redis::RedisError) -> ReconcileError {
+        ReconcileError::RedisError(err)
+    }
+}
+
+impl From<tokio::task::JoinError> for ReconcileError {
+    fn from(err: tokio::task::JoinError) -> ReconcileError {
+        ReconcileError::FailedAnyhow(err.into())
+    }
+}
+
+impl From<anyhow::Error> for ReconcileError {
+    fn from(err: anyhow::Error) -> ReconcileError {
+        ReconcileError::FailedAnyhow(err)
+    }
+}
+
+// owner pod of the stored pod information, None if it is not a pod (e.g. reserved)
+fn parse_pod_information(pod_info: &str) -> Option<PodInformation<'_>> {
+    let info = PodInformation::parse(pod_info)?;
+    if info.namespace == "UnknownNamespace" || info.name == "UnknownPodName" {
+        return None;
+    }
+    Some(info)
+}
+
+#[test]
+fn test_parse_pod_information() {
+    assert_eq!(
+        parse_pod_information("default/test-pod 0123abcd").map(|i| (i.namespace, i.name)),
+        Some(("default", "test-pod"))
+    );
+    assert_eq!(
+        parse_pod_information("UnknownNamespace/UnknownPodName UnknownContainerID"),
+        None
+    );
+    assert_eq!(parse_pod_information("broken"), None);
+}
+
+// the address is leaked if its owner pod is gone, or is replaced by another pod with
+// the same name (e.g. statefulset). Entries without uid are checked by name only.
+fn is_owner_gone(info: &PodInformation, pod: Option<&Pod>) -> bool {
+    match (pod, info.uid) {
+        (None, _) => true,
+        (Some(pod), Some(uid)) => pod.metadata.uid.as_deref() != Some(uid),
+        (Some(_), None) => false,
+    }
+}
+
+#[test]
+fn test_is_owner_gone() {
+    let mut pod = Pod::default();
+    pod.metadata.uid = Some("uid-2".to_string());
+    let info = PodInformation::parse("default/web-0 0123abcd uid-1").unwrap();
+    assert!(is_owner_gone(&info, None));
+    assert!(is_owner_gone(&info, Some(&pod)));
+    pod.metadata.uid = Some("uid-1".to_string());
+    assert!(!is_owner_gone(&info, Some(&pod)));
+    let info = PodInformation::parse("default/web-0 0123abcd").unwrap();
+    assert!(!is_owner_gone(&info, Some(&pod)));
+}
+
+// pod information of all addresses in the pool, as (allocation name, ip, pod information)
+type PodInformations = Vec<(String, IpAddr, String)>;
+
+// redis part of reconcile, before checking pods: initialize the pool and read the
+// pod informations
+fn prepare_pool(con: &mut redis::Connection, networkip: &NetworkIP) -> Result<PodInformations, ReconcileError> {
+    // CNI plugin caches the spec, so keep the cache up to date
+    redisdb::refresh_cached_networkip(con, networkip)?;
+
+    // initialize pool ahead of ADD. Out-of-sync pool is left as is and is
+    // reported in status because re-creating it may hand out used addresses.
+    if !redisdb::check_network_sync(con, networkip)? && redisdb::check_network_bitmap(con, networkip)? == 0 {
+        info!(name = ?networkip.metadata.name, "initializing pool");
+        redisdb::create_network_bitmap(con, networkip)?;
+    }
+    // pools created by older versions lack auto excluded addresses
+    redisdb::mark_excluded_ips(con, networkip)?;
+
+    let mut pod_infos = vec![];
+    for alloc in networkip.spec.ip_allocations.iter() {
+        for (ip, pod_info) in redisdb::get_pod_informations(con, networkip, alloc)? {
+            pod_infos.push((alloc.name.clone(), ip, pod_info));
+        }
+    }
+    Ok(pod_infos)
+}
+
+// addresses whose owner pod does not exist anymore. Pods are listed once per
+// namespace, rather than looked up per address.
+async fn find_leaked_ips(client: &Client, pod_infos: PodInformations) -> Result<PodInformations, ReconcileError> {
+    let namespaces: BTreeSet<&str> = pod_infos
+        .iter()
+        .filter_map(|(_, _, pod_info)| parse_pod_information(pod_info))
+        .map(|info| info.namespace)
+        .collect();
+    let mut pods: HashMap<(String, String), Pod> = HashMap::new();
+    for namespace in namespaces {
+        let api: Api<Pod> = Api::namespaced(client.clone(), namespace);
+        for pod in api.list(&ListParams::default()).await? {
+            let name = pod.metadata.name.clone().unwrap_or_default();
+            pods.insert((namespace.to_string(), name), pod);
+        }
+    }
+    Ok(pod_infos
+        .into_iter()
+        .filter(|(_, _, pod_info)| match parse_pod_information(pod_info) {
+            Some(info) => {
+                let key = (info.namespace.to_string(), info.name.to_string());
+                is_owner_gone(&info, pods.get(&key))
+            }
+            None => false,
+        })
+        .collect())
+}
+
+// release leaked addresses and returns the status of the pool
+fn release_leaked_ips(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    leaked: PodInformations,
+) -> Result<NetworkIPStatus, ReconcileError> {
+    for (alloc_name, ip, pod_info) in leaked {
+        let alloc = match networkip.spec.ip_allocations.iter().find(|a| a.name == alloc_name) {
+            Some(v) => v,
+            None => continue,
+        };
+        // the address may be released and assigned again while pods are listed
+        if redisdb::get_pod_information(con, networkip, alloc, &ip)?.as_ref() != Some(&pod_info) {
+            continue;
+        }
+        info!(%ip, pod = %pod_info, allocation = %alloc.name, "releasing leaked address");
+        redisdb::del_pod_information(con, networkip, alloc, &ip)?;
+        redisdb::return_ip(con, networkip, alloc, ip)?;
+    }
+    Ok(redisdb::get_network_status(con, networkip)?)
+}
+
+pub async fn reconcile(networkip: Arc<NetworkIP>, ctx: Arc<Context>) -> Result<Action, ReconcileError> {
+    let errors = validation::validate_spec(&networkip.spec);
+    if !errors.is_empty() {
+        return Err(ReconcileError::FailedAnyhow(anyhow::anyhow!(
+            "invalid spec: {}",
+            validation::join_errors(&errors)
+        )));
+    }
+    // redis::Connection blocks, so redis parts run on a blocking thread
+    let (con, pod_infos) = {
+        let ctx = ctx.clone();
+        let networkip = networkip.clone();
+        tokio::task::spawn_blocking(move || -> Result<_, ReconcileError> {
+            let mut con = ctx.redis_client.get_connection()?;
+            let pod_infos = prepare_pool(&mut con, &networkip)?;
+            Ok((con, pod_infos))
+        })
+        .await??
+    };
+
+    let leaked = find_leaked_ips(&ctx.client, pod_infos).await?;
+
+    let status = {
+        let networkip = networkip.clone();
+        let mut con = con;
+        tokio::task::spawn_blocking(move || release_leaked_ips(&mut con, &networkip, leaked)).await??
+    };
+    kube_crd::update_status(&ctx.client, &networkip, &status).await?;
+    Ok(Action::requeue(ctx.interval))
+}
+
+pub fn error_policy(err: &ReconcileError, _ctx: Arc<Context>) -> Action {
+    warn!("reconcile failed: {}", err);
+    Action::requeue(Duration::from_secs(30))
+}
+
+pub async fn run(ctx: Context) {
+    let client = ctx.client.clone();
+    let networkips: Api<NetworkIP> = Api::all(client.clone());
+    let pods: Api<Pod> = Api::all(client);
+
+    let controller = Controller::new(networkips, ListParams::default());
+    controller
+        // pod deletion may leave leaked addresses in the pools of its annotation.
+        // Pools of pods without the annotation are checked by periodic reconcile.
+        .watches(pods, ListParams::default(), |pod| {
+            if pod.metadata.deletion_timestamp.is_none() {
+                return vec![];
+            }
+            kube_crd::parse_pod_networks(&pod)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|network| network.split_once('/'))
+                .map(|(namespace, name)| ObjectRef::new(name).within(namespace))
+                .collect()
+        })
+        .shutdown_on_signal()
+        .run(reconcile, error_policy, Arc::new(ctx))
+        .for_each(|res| async move {
+            match res {
+                Ok((obj, _)) => info!("reconciled {}", obj),
+                Err(err) => warn!("reconcile error: {}", err),
+            }
+        })
+        .await;
+}
@@ -0,0 +1,172 @@
+// daemon.rs: unix socket transport between CNI plugin and differance daemon
+//
+// Plugin forwards CNI environment variables and stdin as one JSON request per
+// connection, and prints the output in the response (like the dhcp plugin).
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use libcni::types::types_common::{CNIError, ERR_DECODING_FAILURE};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{info, warn};
+
+pub const DEFAULT_SOCKET: &str = "/run/differance/differance.sock";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Request {
+    /// env specifies CNI_* environment variables of the invocation
+    pub env: HashMap<String, String>,
+    /// stdin specifies network configuration given to the plugin
+    pub stdin: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Response {
+    /// output specifies what the plugin prints to stdout
+    #[serde(default)]
+    pub output: String,
+    /// error specifies error message if the command failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// code specifies CNI error code of the error, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<u32>,
+}
+
+impl Request {
+    // CNI_* variables of this process
+    pub fn from_env(stdin: String) -> Request {
+        Request {
+            env: std::env::vars().filter(|(k, _)| k.starts_with("CNI_")).collect(),
+            stdin,
+        }
+    }
+}
+
+async fn handle_stream<F, Fut>(mut stream: UnixStream, handler: Arc<F>) -> Result<()>
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Result<String>>,
+{
+    let mut buf = vec![];
+    stream.read_to_end(&mut buf).await?;
+    let response = match serde_json::from_slice::<Request>(&buf) {
+        Ok(request) => match handler(request).await {
+            Ok(output) => Response {
+                output,
+                ..Default::default()
+            },
+            Err(e) => Response {
+                output: String::new(),
+                error: Some(e.to_string()),
+                code: e.downcast_ref::<CNIError>().map(|e| e.code),
+            },
+        },
+        Err(e) => Response {
+            output: String::new(),
+            error: Some(format!("invalid request: {}", e)),
+            code: Some(ERR_DECODING_FAILURE),
+        },
+    };
+    stream.write_all(&serde_json::to_vec(&response)?).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// serve requests on the socket until the process is terminated
+pub async fn serve<F, Fut>(socket: &str, handler: F) -> Result<()>
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<String>> + Send,
+{
+    let path = Path::new(socket);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // stale socket from previous run
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("serving CNI requests on {}", socket);
+
+    let handler = Arc::new(handler);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_stream(stream, handler).await {
+                warn!("failed to handle request: {}", e);
+            }
+        });
+    }
+}
+
+// send the request to the daemon and wait for the response. None if no daemon
+// listens on the socket (e.g. stale socket of crashed daemon), then the request
+// is not sent at all and the caller may run it by itself.
+pub async fn forward(socket: &str, request: &Request) -> Result<Option<Response>> {
+    let mut stream = match UnixStream::connect(socket).await {
+        Ok(v) => v,
+        Err(e) if matches!(e.kind(), ErrorKind::ConnectionRefused | ErrorKind::NotFound) => {
+            warn!(socket, "daemon is not running: {}", e);
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    stream.write_all(&serde_json::to_vec(request)?).await?;
+    stream.shutdown().await?;
+    let mut buf = vec![];
+    stream.read_to_end(&mut buf).await?;
+    Ok(Some(serde_json::from_slice(&buf)?))
+}
+
+#[tokio::test]
+async fn test_forward() {
+    let socket = std::env::temp_dir().join(format!("differance-test-{}.sock", std::process::id()));
+    let socket = socket.to_str().unwrap().to_string();
+    let server_socket = socket.clone();
+    tokio::spawn(async move {
+        serve(&server_socket, |request: Request| async move {
+            match request.env.get("CNI_COMMAND").map(|s| s.as_str()) {
+                Some("ADD") => Ok(request.stdin),
+                Some("DEL") => Err(CNIError::new(libcni::types::types_common::ERR_TRY_AGAIN_LATER, "busy", "").into()),
+                _ => Err(anyhow::anyhow!("unknown command")),
+            }
+        })
+        .await
+    });
+    while !Path::new(&socket).exists() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    let mut request = Request {
+        env: [("CNI_COMMAND".to_string(), "ADD".to_string())].into_iter().collect(),
+        stdin: "{}".to_string(),
+    };
+    let response = forward(&socket, &request).await.unwrap().unwrap();
+    assert_eq!(response.output, "{}");
+    assert!(response.error.is_none());
+
+    request.env.insert("CNI_COMMAND".to_string(), "VERSION".to_string());
+    let response = forward(&socket, &request).await.unwrap().unwrap();
+    assert_eq!(response.error.as_deref(), Some("unknown command"));
+    assert!(response.code.is_none());
+
+    request.env.insert("CNI_COMMAND".to_string(), "DEL".to_string());
+    let response = forward(&socket, &request).await.unwrap().unwrap();
+    assert_eq!(response.code, Some(libcni::types::types_common::ERR_TRY_AGAIN_LATER));
+    let _ = std::fs::remove_file(&socket);
+
+    // stale socket, left by the daemon which is gone
+    drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+    assert!(forward(&socket, &request).await.unwrap().is_none());
+    let _ = std::fs::remove_file(&socket);
+}
@@ -1,4 +1,5 @@
 use async_std::task::block_on;
+use std::collections::BTreeMap;
 use std::net::IpAddr;
 use std::time::Duration;
 
@@ -6,13 +7,15 @@ extern crate ipnet;
 use libcni::ipnet::IPNet;
 use ipnet::IpNet;
 
-use libcni::types::types_common::Route as CNIRoute;
+use libcni::types::types_110::CNI110Route;
+use libcni::types::types_common::DNS as CNIDNS;
 
 use anyhow::Result; // bail may be used.
 use either::{Left, Right};
+use k8s_openapi::api::core::v1::{Namespace, Pod};
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::{
-    api::{Api, DeleteParams, PostParams},
+    api::{Api, DeleteParams, ListParams, Patch, PatchParams, PostParams},
     config::{KubeConfigOptions, Kubeconfig},
     core::crd::CustomResourceExt,
     Client, Config, CustomResource,
@@ -24,6 +27,8 @@ use validator::Validate;
 
 pub const CRD_NAME: &str = "networkips.xxxx.cni.cncf.io";
 pub const CRD_VERSION: &str = "v1alpha1";
+// pod annotation to choose networkips, comma separated <namespace>/<name> or <name> in pod's namespace
+pub const NETWORK_ANNOTATION: &str = "differance.io/network";
 
 // NetworkIP CRD definition
 
@@ -64,15 +69,28 @@ pub struct NetworkIPAllocations {
     /// route specifies IP route information for the network
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub route: Vec<Route>,
+    /// disableAutoExclude stops reserving network/broadcast/gateway addresses automatically
+    #[serde(rename = "disableAutoExclude", default, skip_serializing_if = "std::ops::Not::not")]
+    pub disable_auto_exclude: bool,
+    /// blockSize enables block affinity: each node claims blocks of blockSize addresses
+    /// (multiple of 8) from range start and allocates within them. Align range start
+    /// and use power of two to make blocks routable per node.
+    #[serde(rename = "blockSize", default, skip_serializing_if = "Option::is_none")]
+    pub block_size: Option<u32>,
 }
 
 impl NetworkIPAllocations {
-    pub fn get_cni_route(&self) -> Vec<CNIRoute> {
+    pub fn get_cni_route(&self) -> Vec<CNI110Route> {
         self.route.iter().map(|r| {
             let dst: IPNet = r.dst.parse().unwrap();
-            CNIRoute{
-                dst: dst,
-                gw: r.gw,
+            CNI110Route{
+                dst,
+                gw: Some(r.gw),
+                mtu: None,
+                advmss: None,
+                priority: None,
+                table: None,
+                scope: None,
             }
         }).collect()
     }
@@ -81,21 +99,287 @@ impl NetworkIPAllocations {
         let subnet: IPNet = self.subnet.parse().unwrap();
         subnet.get_network_ip()
     }
+
+    // check whether the ip is in the allocatable range (baseip to lastip)
+    pub fn contains_ip(&self, ip: &IpAddr) -> bool {
+        let subnet: IpNet = self.subnet.parse().unwrap();
+        subnet.contains(ip)
+            && *ip >= get_ipallocation_baseip(self)
+            && *ip <= get_ipallocation_lastip(self)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, Validate, JsonSchema)]
+pub struct NetworkIPDNS {
+    /// nameservers specifies DNS server addresses
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nameservers: Vec<String>,
+    /// domain specifies local domain name
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub domain: String,
+    /// search specifies search domains
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub search: Vec<String>,
+    /// options specifies resolver options
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+impl NetworkIPDNS {
+    // fields in NetworkIP take precedence, empty fields are filled from netconf 'dns'
+    pub fn get_cni_dns(&self, netconf_dns: &CNIDNS) -> CNIDNS {
+        CNIDNS {
+            nameservers: if self.nameservers.is_empty() {
+                netconf_dns.nameservers.clone()
+            } else {
+                self.nameservers.clone()
+            },
+            domain: if self.domain.is_empty() {
+                netconf_dns.domain.clone()
+            } else {
+                self.domain.clone()
+            },
+            search: if self.search.is_empty() {
+                netconf_dns.search.clone()
+            } else {
+                self.search.clone()
+            },
+            options: if self.options.is_empty() {
+                netconf_dns.options.clone()
+            } else {
+                self.options.clone()
+            },
+        }
+    }
+}
+
+#[test]
+fn test_get_cni_dns() {
+    let dns = NetworkIPDNS {
+        nameservers: vec!["10.1.1.53".to_string()],
+        search: vec!["example.com".to_string()],
+        ..Default::default()
+    };
+    let netconf_dns = CNIDNS {
+        nameservers: vec!["8.8.8.8".to_string()],
+        domain: "cluster.local".to_string(),
+        search: vec![],
+        options: vec!["ndots:5".to_string()],
+    };
+    let cni_dns = dns.get_cni_dns(&netconf_dns);
+    assert_eq!(cni_dns.nameservers, vec!["10.1.1.53".to_string()]);
+    assert_eq!(cni_dns.domain, "cluster.local");
+    assert_eq!(cni_dns.search, vec!["example.com".to_string()]);
+    assert_eq!(cni_dns.options, vec!["ndots:5".to_string()]);
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Validate, JsonSchema)]
+pub struct NetworkIPLabelSelectorRequirement {
+    /// key specifies the label key
+    pub key: String,
+    /// operator specifies one of In, NotIn, Exists and DoesNotExist
+    pub operator: String,
+    /// values specifies label values for In and NotIn
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, Validate, JsonSchema)]
+pub struct NetworkIPLabelSelector {
+    /// matchLabels specifies labels which must match exactly
+    #[serde(rename = "matchLabels", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub match_labels: BTreeMap<String, String>,
+    /// matchExpressions specifies label selector requirements
+    #[serde(rename = "matchExpressions", default, skip_serializing_if = "Vec::is_empty")]
+    pub match_expressions: Vec<NetworkIPLabelSelectorRequirement>,
+}
+
+impl NetworkIPLabelSelector {
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.match_labels.iter().all(|(k, v)| labels.get(k) == Some(v))
+            && self.match_expressions.iter().all(|e| {
+                let value = labels.get(&e.key);
+                match e.operator.as_str() {
+                    "In" => value.is_some_and(|v| e.values.contains(v)),
+                    "NotIn" => !value.is_some_and(|v| e.values.contains(v)),
+                    "Exists" => value.is_some(),
+                    "DoesNotExist" => value.is_none(),
+                    _ => false,
+                }
+            })
+    }
+}
+
+#[test]
+fn test_label_selector_matches() {
+    let selector = NetworkIPLabelSelector {
+        match_labels: [("tenant".to_string(), "a".to_string())].into_iter().collect(),
+        match_expressions: vec![NetworkIPLabelSelectorRequirement {
+            key: "env".to_string(),
+            operator: "NotIn".to_string(),
+            values: vec!["prod".to_string()],
+        }],
+    };
+    let labels = |l: &[(&str, &str)]| -> BTreeMap<String, String> {
+        l.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    };
+    assert!(selector.matches(&labels(&[("tenant", "a")])));
+    assert!(selector.matches(&labels(&[("tenant", "a"), ("env", "dev")])));
+    assert!(!selector.matches(&labels(&[("tenant", "a"), ("env", "prod")])));
+    assert!(!selector.matches(&labels(&[("tenant", "b")])));
 }
 
 //#[kube(printcolumn = r#"{"name":"Namespace", "jsonPath": ".spec.metadata.namespace", "type": "string"}"#)]
-//#[kube(status = "NetworkIPStatus")]
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, Validate, JsonSchema)]
 #[kube(
     group = "xxxx.cni.cncf.io",
     version = "v1alpha1",
     kind = "NetworkIP",
-    namespaced
+    namespaced,
+    status = "NetworkIPStatus",
+    printcolumn = r#"{"name":"Capacity", "jsonPath": ".status.capacity", "type": "integer"}"#,
+    printcolumn = r#"{"name":"Allocated", "jsonPath": ".status.allocated", "type": "integer"}"#,
+    printcolumn = r#"{"name":"Free", "jsonPath": ".status.free", "type": "integer"}"#,
+    printcolumn = r#"{"name":"Ready", "jsonPath": ".status.conditions[?(@.type==\"Ready\")].status", "type": "string"}"#,
+    printcolumn = r#"{"name":"Age", "jsonPath": ".metadata.creationTimestamp", "type": "date"}"#
 )]
 pub struct NetworkIPSpec {
     /// ipAllocations xxxx
     #[serde(rename = "ipAllocations")]
     pub ip_allocations: Vec<NetworkIPAllocations>,
+    /// dns specifies DNS configuration returned in CNI result
+    #[serde(default)]
+    pub dns: NetworkIPDNS,
+    /// allowedNamespaces specifies namespaces whose pods may use this network
+    #[serde(rename = "allowedNamespaces", default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_namespaces: Vec<String>,
+    /// namespaceSelector specifies labels of namespaces whose pods may use this network
+    #[serde(rename = "namespaceSelector", default, skip_serializing_if = "Option::is_none")]
+    pub namespace_selector: Option<NetworkIPLabelSelector>,
+}
+
+// NetworkIP status definition
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct NetworkIPAllocationStatus {
+    /// name specifies identifier of the allocations
+    pub name: String,
+    /// capacity specifies the number of addresses in the range
+    pub capacity: u64,
+    /// allocated specifies the number of addresses assigned to pods (or reserved)
+    pub allocated: u64,
+    /// free specifies the number of addresses available for allocation
+    pub free: u64,
+    /// excluded specifies the number of addresses excluded from allocation
+    pub excluded: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+pub struct NetworkIPCondition {
+    /// type specifies condition type: Ready, Exhausted or OutOfSync
+    #[serde(rename = "type")]
+    pub r#type: String,
+    /// status specifies condition status: True or False
+    pub status: String,
+    /// lastTransitionTime specifies the time when status is changed
+    #[serde(rename = "lastTransitionTime", default)]
+    pub last_transition_time: String,
+    /// reason specifies the reason of the condition
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub reason: String,
+    /// message specifies human readable detail of the condition
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+pub struct NetworkIPStatus {
+    /// capacity specifies the sum of the capacity of each allocations
+    pub capacity: u64,
+    /// allocated specifies the sum of the allocated addresses of each allocations
+    pub allocated: u64,
+    /// free specifies the sum of the free addresses of each allocations
+    pub free: u64,
+    /// allocations specifies per-allocation utilization
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allocations: Vec<NetworkIPAllocationStatus>,
+    /// conditions specifies the current state of the NetworkIP
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<NetworkIPCondition>,
+}
+
+impl NetworkIPStatus {
+    // build status from per-allocation utilization. 'in_sync' is false when the
+    // redis database does not match with the spec. lastTransitionTime is carried
+    // from previous status if the condition is not changed.
+    pub fn new(
+        networkip: &NetworkIP,
+        allocations: Vec<NetworkIPAllocationStatus>,
+        in_sync: bool,
+    ) -> NetworkIPStatus {
+        let exhausted: Vec<&str> = allocations
+            .iter()
+            .filter(|a| a.free == 0)
+            .map(|a| a.name.as_str())
+            .collect();
+        let conditions = vec![
+            if in_sync {
+                ("Ready", true, "PoolInitialized", "".to_string())
+            } else {
+                ("Ready", false, "PoolNotInitialized", "pool is not initialized in redis".to_string())
+            },
+            if exhausted.is_empty() {
+                ("Exhausted", false, "AddressAvailable", "".to_string())
+            } else {
+                ("Exhausted", true, "NoFreeAddress", format!("no free address in {}", exhausted.join(",")))
+            },
+            if in_sync {
+                ("OutOfSync", false, "InSync", "".to_string())
+            } else {
+                ("OutOfSync", true, "DatabaseMismatch", "redis database does not match with spec".to_string())
+            },
+        ];
+        let prev_conditions = match &networkip.status {
+            Some(s) => s.conditions.clone(),
+            None => vec![],
+        };
+        let now = k8s_openapi::chrono::Utc::now().to_rfc3339();
+
+        NetworkIPStatus {
+            capacity: allocations.iter().map(|a| a.capacity).sum(),
+            allocated: allocations.iter().map(|a| a.allocated).sum(),
+            free: allocations.iter().map(|a| a.free).sum(),
+            allocations,
+            conditions: conditions
+                .into_iter()
+                .map(|(cond_type, cond_status, reason, message)| {
+                    let status = if cond_status { "True" } else { "False" }.to_string();
+                    let last_transition_time = match prev_conditions
+                        .iter()
+                        .find(|c| c.r#type == cond_type && c.status == status)
+                    {
+                        Some(c) => c.last_transition_time.clone(),
+                        None => now.clone(),
+                    };
+                    NetworkIPCondition {
+                        r#type: cond_type.to_string(),
+                        status,
+                        last_transition_time,
+                        reason: reason.to_string(),
+                        message,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[test]
+fn test_crd_status_subresource() {
+    let crd = NetworkIP::crd();
+    let version = &crd.spec.versions[0];
+    assert!(version.subresources.as_ref().unwrap().status.is_some());
+    assert_eq!(version.additional_printer_columns.as_ref().unwrap().len(), 5);
 }
 
 pub fn get_ipallocation_names(networkip: &NetworkIP) -> Vec<String> {
@@ -125,18 +409,156 @@ pub fn get_ipallocation_baseip(allocations: &NetworkIPAllocations) -> IpAddr {
     }
 }
 
-pub async fn get_crd(client: &Client, networkip_namespacedname: &str) -> Result<NetworkIP> {
-    let networkip_namevec: Vec<&str> = networkip_namespacedname.split('/').collect();
-    let (networkip_namespace, networkip_name) = match networkip_namevec.len() {
-        2 => (networkip_namevec[0], networkip_namevec[1]),
-        1 => ("default", networkip_namevec[0]),
-        _ => {
-            return Err(anyhow::anyhow!(
-                "cannot find networkip {}",
-                networkip_namespacedname
-            ))
+// returns the last address which can be allocated: range end, or the last host
+// address of the subnet if no end is given
+pub fn get_ipallocation_lastip(allocations: &NetworkIPAllocations) -> IpAddr {
+    match allocations.range.as_ref().and_then(|r| r.end) {
+        Some(end) => end,
+        None => {
+            let ip1: IpNet = allocations.subnet.parse().unwrap();
+            ip1.hosts().last().unwrap()
+        }
+    }
+}
+
+// returns the number of addresses between baseip and lastip
+pub fn get_ipallocation_capacity(allocations: &NetworkIPAllocations) -> u64 {
+    let baseip = get_ipallocation_baseip(allocations);
+    let lastip = get_ipallocation_lastip(allocations);
+    let size: u128 = match (baseip, lastip) {
+        (IpAddr::V4(base), IpAddr::V4(last)) if base <= last => {
+            (u32::from(last) - u32::from(base)) as u128 + 1
         }
+        (IpAddr::V6(base), IpAddr::V6(last)) if base <= last => {
+            (u128::from(last) - u128::from(base)).saturating_add(1)
+        }
+        _ => 0,
+    };
+    u64::try_from(size).unwrap_or(u64::MAX)
+}
+
+#[test]
+fn test_get_ipallocation_capacity() {
+    let alloc = NetworkIPAllocations {
+        name: "test".to_string(),
+        subnet: "10.1.1.0/24".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(get_ipallocation_capacity(&alloc), 254);
+
+    let alloc = NetworkIPAllocations {
+        name: "test".to_string(),
+        subnet: "10.1.1.0/24".to_string(),
+        range: Some(NetworkIPRange {
+            start: "10.1.1.100".parse().unwrap(),
+            end: Some("10.1.1.250".parse().unwrap()),
+        }),
+        ..Default::default()
+    };
+    assert_eq!(get_ipallocation_capacity(&alloc), 151);
+}
+
+// returns reserved addresses of the subnet: network and broadcast address (IPv4),
+// subnet-router anycast address (IPv6) and gateway
+pub fn get_ipallocation_reserved(allocations: &NetworkIPAllocations) -> Vec<IpAddr> {
+    let subnet: IpNet = allocations.subnet.parse().unwrap();
+    let mut reserved = match subnet {
+        IpNet::V4(v4net) if v4net.prefix_len() < 31 => vec![
+            IpAddr::V4(v4net.network()),
+            IpAddr::V4(v4net.broadcast()),
+        ],
+        IpNet::V6(v6net) if v6net.prefix_len() < 127 => vec![IpAddr::V6(v6net.network())],
+        _ => vec![],
+    };
+    if let Some(gw) = allocations.gateway {
+        reserved.push(gw);
+    }
+    reserved
+}
+
+// returns addresses which should be marked as used at pool initialization. Addresses
+// outside of the subnet or out of baseip..lastip are dropped because they have no bit
+// in the bitmap.
+pub fn get_ipallocation_excludes(allocations: &NetworkIPAllocations) -> Vec<IpAddr> {
+    let subnet: IpNet = allocations.subnet.parse().unwrap();
+    let baseip = get_ipallocation_baseip(allocations);
+    let lastip = get_ipallocation_lastip(allocations);
+    let mut excludes = allocations.exclude.clone();
+    if !allocations.disable_auto_exclude {
+        excludes.extend(get_ipallocation_reserved(allocations));
+    }
+    excludes.sort();
+    excludes.dedup();
+    excludes
+        .into_iter()
+        .filter(|ip| subnet.contains(ip) && *ip >= baseip && *ip <= lastip)
+        .collect()
+}
+
+#[test]
+fn test_get_ipallocation_excludes_v4() {
+    let alloc = NetworkIPAllocations {
+        name: "test".to_string(),
+        subnet: "10.1.1.0/24".to_string(),
+        gateway: Some("10.1.1.254".parse().unwrap()),
+        exclude: vec!["10.1.1.200".parse().unwrap()],
+        ..Default::default()
+    };
+    let excludes: Vec<IpAddr> = vec![
+        "10.1.1.200".parse().unwrap(),
+        "10.1.1.254".parse().unwrap(),
+    ];
+    assert_eq!(get_ipallocation_excludes(&alloc), excludes);
+    // broadcast address is beyond the last allocatable address, so it has no bit
+    // to set and is never handed out
+    let broadcast: IpAddr = "10.1.1.255".parse().unwrap();
+    assert!(get_ipallocation_reserved(&alloc).contains(&broadcast));
+    assert!(get_ipallocation_lastip(&alloc) < broadcast);
+}
+
+#[test]
+fn test_get_ipallocation_excludes_v6_range() {
+    let alloc = NetworkIPAllocations {
+        name: "test".to_string(),
+        subnet: "10::/64".to_string(),
+        gateway: Some("10::1".parse().unwrap()),
+        range: Some(NetworkIPRange {
+            start: "10::1".parse().unwrap(),
+            end: Some("10::ff".parse().unwrap()),
+        }),
+        ..Default::default()
     };
+    let excludes: Vec<IpAddr> = vec!["10::1".parse().unwrap()];
+    assert_eq!(get_ipallocation_excludes(&alloc), excludes);
+}
+
+#[test]
+fn test_get_ipallocation_excludes_disabled() {
+    let alloc = NetworkIPAllocations {
+        name: "test".to_string(),
+        subnet: "10.1.1.0/24".to_string(),
+        gateway: Some("10.1.1.254".parse().unwrap()),
+        disable_auto_exclude: true,
+        ..Default::default()
+    };
+    assert!(get_ipallocation_excludes(&alloc).is_empty());
+}
+
+// split '<namespace>/<name>' ('default' namespace if omitted)
+pub fn parse_namespacedname(networkip_namespacedname: &str) -> Result<(&str, &str)> {
+    let networkip_namevec: Vec<&str> = networkip_namespacedname.split('/').collect();
+    match networkip_namevec.len() {
+        2 => Ok((networkip_namevec[0], networkip_namevec[1])),
+        1 => Ok(("default", networkip_namevec[0])),
+        _ => Err(anyhow::anyhow!(
+            "cannot find networkip {}",
+            networkip_namespacedname
+        )),
+    }
+}
+
+pub async fn get_crd(client: &Client, networkip_namespacedname: &str) -> Result<NetworkIP> {
+    let (networkip_namespace, networkip_name) = parse_namespacedname(networkip_namespacedname)?;
     let network_ip_crd: Api<NetworkIP> = Api::namespaced(client.clone(), networkip_namespace);
 
     match network_ip_crd.get(networkip_name).await {
@@ -148,6 +570,112 @@ pub async fn get_crd(client: &Client, networkip_namespacedname: &str) -> Result<
     }
 }
 
+// returns networkips requested by the pod annotation (comma separated), as <namespace>/<name>
+pub async fn get_pod_networks(client: &Client, namespace: &str, name: &str) -> Result<Option<Vec<String>>> {
+    let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+    let pod = pods.get(name).await?;
+    Ok(parse_pod_networks(&pod))
+}
+
+// networkips in the pod annotation as '<namespace>/<name>'. Network without
+// namespace is in the namespace of the pod.
+pub fn parse_pod_networks(pod: &Pod) -> Option<Vec<String>> {
+    let namespace = pod.metadata.namespace.as_deref().unwrap_or("default");
+    pod.metadata
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(NETWORK_ANNOTATION))
+        .map(|networks| {
+            networks
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(|network| {
+                    if network.contains('/') {
+                        network.to_string()
+                    } else {
+                        format!("{}/{}", namespace, network)
+                    }
+                })
+                .collect()
+        })
+}
+
+// pods in the namespace may use the networkip if the namespace is in allowedNamespaces
+// or matches namespaceSelector. Every namespace is allowed if neither is specified.
+pub async fn check_namespace_allowed(client: Option<&Client>, networkip: &NetworkIP, namespace: Option<&str>) -> Result<()> {
+    let spec = &networkip.spec;
+    if spec.allowed_namespaces.is_empty() && spec.namespace_selector.is_none() {
+        return Ok(());
+    }
+    let denied = |namespace: &str| {
+        anyhow::anyhow!(
+            "permission denied: namespace {} is not allowed to use networkip {}/{}",
+            namespace,
+            networkip.metadata.namespace.clone().unwrap_or_default(),
+            networkip.metadata.name.clone().unwrap_or_default()
+        )
+    };
+    let namespace = namespace.ok_or_else(|| denied("(unknown)"))?;
+    if spec.allowed_namespaces.iter().any(|n| n == namespace) {
+        return Ok(());
+    }
+    if let (Some(selector), Some(client)) = (&spec.namespace_selector, client) {
+        let namespaces: Api<Namespace> = Api::all(client.clone());
+        let labels = namespaces.get(namespace).await?.metadata.labels.unwrap_or_default();
+        if selector.matches(&labels) {
+            return Ok(());
+        }
+    }
+    Err(denied(namespace))
+}
+
+// parse NetworkIPSpec from YAML (or JSON). NetworkIP manifest is also accepted
+// and its 'spec' is used.
+pub fn parse_spec(document: &str) -> Result<NetworkIPSpec> {
+    let mut value: serde_yaml::Value = serde_yaml::from_str(document)?;
+    if let Some(spec) = value.get("spec") {
+        value = spec.clone();
+    }
+    Ok(serde_yaml::from_value(value)?)
+}
+
+#[test]
+fn test_parse_spec() {
+    let spec = r#"
+ipAllocations:
+  - name: testIPv4
+    subnet: 10.1.1.0/24
+"#;
+    assert_eq!(parse_spec(spec).unwrap().ip_allocations[0].name, "testIPv4");
+    let manifest = format!(
+        "apiVersion: xxxx.cni.cncf.io/v1alpha1\nkind: NetworkIP\nmetadata:\n  name: test\nspec:{}",
+        spec.replace('\n', "\n  ")
+    );
+    assert_eq!(parse_spec(&manifest).unwrap().ip_allocations[0].subnet, "10.1.1.0/24");
+}
+
+pub async fn list_crd(client: &Client) -> Result<Vec<NetworkIP>> {
+    let network_ip_crd: Api<NetworkIP> = Api::all(client.clone());
+    Ok(network_ip_crd.list(&ListParams::default()).await?.items)
+}
+
+pub async fn update_status(client: &Client, networkip: &NetworkIP, status: &NetworkIPStatus) -> Result<()> {
+    let network_ip_crd: Api<NetworkIP> = Api::namespaced(
+        client.clone(),
+        networkip.metadata.namespace.as_deref().unwrap_or("default"),
+    );
+    let patch = serde_json::json!({ "status": status });
+    network_ip_crd
+        .patch_status(
+            networkip.metadata.name.as_deref().unwrap_or_default(),
+            &PatchParams::default(),
+            &Patch::Merge(&patch),
+        )
+        .await?;
+    Ok(())
+}
+
 pub async fn check_crd(client: &Client) -> bool {
     // Manage CRDs first
     let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
@@ -182,14 +710,94 @@ pub async fn delete_crd(client: &Client) -> Result<()> {
     Ok(())
 }
 
-// create CRDs from kubeconfig
-pub async fn create_crd_kubeconfig(kubeconfig: &str) -> Result<()> {
+// create kube client from kubeconfig
+pub async fn get_client_kubeconfig(kubeconfig: &str) -> Result<Client> {
     let config = Config::from_custom_kubeconfig(
         Kubeconfig::read_from(kubeconfig)?,
         &KubeConfigOptions::default(),
     )
     .await?;
-    let client = Client::try_from(config)?;
+    Ok(Client::try_from(config)?)
+}
+
+// default service-account token directory, which has 'token' and 'ca.crt'
+pub const DEFAULT_SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+// kubeconfig which authenticates with the token in the service-account directory
+pub fn get_service_account_kubeconfig(dir: &str, server: &str) -> Result<Kubeconfig> {
+    let dir = std::path::Path::new(dir);
+    let ca = dir.join("ca.crt");
+    let mut cluster = serde_json::json!({ "server": server });
+    if ca.exists() {
+        cluster["certificate-authority"] = serde_json::json!(ca);
+    }
+    Ok(serde_json::from_value(serde_json::json!({
+        "clusters": [{ "name": "differance", "cluster": cluster }],
+        "users": [{ "name": "differance", "user": { "tokenFile": dir.join("token") } }],
+        "contexts": [{ "name": "differance", "context": { "cluster": "differance", "user": "differance" } }],
+        "current-context": "differance",
+    }))?)
+}
+
+#[test]
+fn test_get_service_account_kubeconfig() {
+    let kubeconfig =
+        get_service_account_kubeconfig("/etc/differance/serviceaccount", "https://10.0.0.1:6443").unwrap();
+    assert_eq!(kubeconfig.clusters[0].cluster.server, "https://10.0.0.1:6443");
+    assert_eq!(
+        kubeconfig.auth_infos[0].auth_info.token_file.as_deref(),
+        Some("/etc/differance/serviceaccount/token")
+    );
+    assert_eq!(kubeconfig.current_context.as_deref(), Some("differance"));
+}
+
+// create kube client, trying in order: kubeconfig path, KUBECONFIG environment
+// variable and service-account token directory. 'server' overrides API server URL.
+pub async fn get_client(kubeconfig: Option<&str>, service_account_dir: &str, server: Option<&str>) -> Result<Client> {
+    let kubeconfig = match kubeconfig {
+        Some(path) => Kubeconfig::read_from(path)?,
+        None => match Kubeconfig::from_env()? {
+            Some(v) => v,
+            None => {
+                if !std::path::Path::new(service_account_dir).join("token").exists() {
+                    return Err(anyhow::anyhow!(
+                        "no kubeconfig, KUBECONFIG or service-account token in {} found",
+                        service_account_dir
+                    ));
+                }
+                // in-cluster environment variables are usually absent on the host
+                let server = match server {
+                    Some(v) => v.to_string(),
+                    None => match (
+                        std::env::var("KUBERNETES_SERVICE_HOST"),
+                        std::env::var("KUBERNETES_SERVICE_PORT"),
+                    ) {
+                        (Ok(host), Ok(port)) => format!("https://{}", join_host_port(&host, &port)),
+                        _ => return Err(anyhow::anyhow!("API server URL is required for service-account token")),
+                    },
+                };
+                get_service_account_kubeconfig(service_account_dir, &server)?
+            }
+        },
+    };
+    let mut config = Config::from_custom_kubeconfig(kubeconfig, &KubeConfigOptions::default()).await?;
+    if let Some(server) = server {
+        config.cluster_url = server.parse()?;
+    }
+    Ok(Client::try_from(config)?)
+}
+
+fn join_host_port(host: &str, port: &str) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+// create CRDs from kubeconfig
+pub async fn create_crd_kubeconfig(kubeconfig: &str) -> Result<()> {
+    let client = get_client_kubeconfig(kubeconfig).await?;
 
     let _ = block_on(delete_crd(&client));
     //info!("end delete");
@@ -0,0 +1,10 @@
+pub mod admin;
+pub mod backup;
+pub mod controller;
+pub mod daemon;
+pub mod kube_crd;
+pub mod logging;
+pub mod metrics;
+pub mod redisdb;
+pub mod validation;
+pub mod webhook;
@@ -0,0 +1,99 @@
+// logging.rs: tracing subscriber setup shared by the CNI plugin and daemons
+//
+// CNI plugin writes its result to stdout, so logs must never go there.
+// They go to stderr by default, or to the given log file.
+use std::fs::File;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use tracing::Subscriber;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::MakeWriter;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow::anyhow!("unknown log format: {}", s)),
+        }
+    }
+}
+
+fn build<W>(level: LevelFilter, format: LogFormat, writer: W, ansi: bool) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        // one JSON object per line, span fields (e.g. command) are in "span"
+        LogFormat::Json => Box::new(builder.json().flatten_event(true).with_span_list(false).finish()),
+    }
+}
+
+// install global subscriber. Logs go to 'file' (appended) if given, otherwise stderr.
+// Unwritable log file must not fail CNI command, so stderr is used instead.
+pub fn init(level: &str, format: LogFormat, file: Option<&str>) -> Result<()> {
+    let level = LevelFilter::from_str(level).map_err(|_| anyhow::anyhow!("unknown log level: {}", level))?;
+    let (subscriber, file_error) = match file.map(|path| File::options().create(true).append(true).open(path)) {
+        Some(Ok(file)) => (build(level, format, BoxMakeWriter::new(Mutex::new(file)), false), None),
+        Some(Err(e)) => (build(level, format, BoxMakeWriter::new(std::io::stderr), true), Some(e)),
+        None => (build(level, format, BoxMakeWriter::new(std::io::stderr), true), None),
+    };
+    tracing::subscriber::set_global_default(subscriber)?;
+    if let Some(e) = file_error {
+        tracing::warn!("failed to open log file {}: {}, logging to stderr", file.unwrap_or_default(), e);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_json_format() {
+    use std::io;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buffer = Buffer::default();
+    let writer = buffer.clone();
+    let subscriber = build(LevelFilter::INFO, LogFormat::Json, move || writer.clone(), false);
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!("cni", command = "ADD", container_id = "abcd");
+        let _enter = span.enter();
+        tracing::debug!("filtered out");
+        tracing::info!(ip = "10.1.1.1", "allocated");
+    });
+
+    let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 1);
+    let log: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(log["level"], "INFO");
+    assert_eq!(log["span"]["command"], "ADD");
+    assert_eq!(log["span"]["container_id"], "abcd");
+    assert_eq!(log["ip"], "10.1.1.1");
+    assert_eq!(log["message"], "allocated");
+}
@@ -1,28 +1,30 @@
-use std::fs::File;
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 extern crate redis;
 use libcni::skel::NetConf as CNINetConf;
+use libcni::args::K8sArgs;
 use libcni::skel::*;
 use libcni::ipnet::IPNet;
-use libcni::types::types_100::*;
-use libcni::types::types_common::DNS;
+use libcni::types::types_110::*;
+use libcni::types::types_common::{
+    CNIError, ERR_DECODING_FAILURE, ERR_INTERNAL, ERR_INVALID_ENVIRONMENT_VARIABLES,
+    ERR_INVALID_NETWORK_CONFIG, ERR_IO_FAILURE, ERR_PLUGIN_NOT_AVAILABLE,
+};
+use libcni::version::PluginInfo;
 
 use anyhow::Result; // bail may be used.
-use clap::{App, Arg, ArgAction};
-use kube::{
-    //api::{Api}, //, DeleteParams, ListParams, PostParams, ResourceExt},
-    config::{KubeConfigOptions, Kubeconfig},
-    //core::crd::CustomResourceExt,
-    Client,
-    Config, //CustomResource,
-};
+use clap::{App, Arg, ArgAction, ArgMatches};
+use kube::Client;
 use redis::Client as RedisClient;
 use serde::Deserialize;
 
-//use crate::kube_crd::NetworkIP;
-mod kube_crd;
-mod redisdb;
+use differance::kube_crd::{NetworkIP, NetworkIPAllocations, NetworkIPDNS, NetworkIPSpec};
+use differance::{admin, backup, daemon, kube_crd, logging, metrics, redisdb, validation};
+use tracing::{debug, error, info, warn, Instrument};
 
 #[derive(Deserialize, Debug)]
 struct IPAMConfig {
@@ -34,12 +36,80 @@ struct IPAMConfig {
     r#type: String,
     #[serde(rename = "redis_ip")]
     redis_ip: String,
-    #[serde(rename = "kubeconfig")]
-    kubeconfig: String,
-    #[serde(rename = "network")]
+    /// kubeconfig specifies kubeconfig path (KUBECONFIG or service account if omitted)
+    #[serde(rename = "kubeconfig", default)]
+    kubeconfig: Option<String>,
+    /// service_account_dir specifies the directory which has service-account 'token' and 'ca.crt'
+    #[serde(rename = "service_account_dir", default = "default_service_account_dir")]
+    service_account_dir: String,
+    /// kube_api_server overrides kubernetes API server URL
+    #[serde(rename = "kube_api_server", default)]
+    kube_api_server: Option<String>,
+    /// network specifies networkip, <namespace>/<name>. With static pool, it names
+    /// the pool in redis ('static/<netconf name>' if omitted)
+    #[serde(rename = "network", default)]
     network: String,
-    #[serde(rename = "debug_file", default)]
-    debug_file: String,
+    /// networks specifies networkips to get addresses from, instead of network
+    #[serde(rename = "networks", default)]
+    networks: Vec<String>,
+    /// ipAllocations specifies static pool instead of networkip
+    #[serde(rename = "ipAllocations", default)]
+    ip_allocations: Option<Vec<NetworkIPAllocations>>,
+    /// dns specifies DNS configuration of static pool
+    #[serde(rename = "dns", default)]
+    dns: NetworkIPDNS,
+    /// spec_file specifies YAML file of static pool (NetworkIPSpec or NetworkIP)
+    #[serde(rename = "spec_file", default)]
+    spec_file: Option<String>,
+    /// daemon_socket specifies socket of differance daemon. Requests are forwarded
+    /// to the daemon if it listens on the socket
+    #[serde(rename = "daemon_socket", default = "default_daemon_socket")]
+    daemon_socket: String,
+    /// node_name identifies the node in block affinity mode (hostname if omitted)
+    #[serde(rename = "node_name", default)]
+    node_name: Option<String>,
+    /// cache_ttl specifies seconds to cache networkip in redis (0 disables cache)
+    #[serde(rename = "cache_ttl", default = "default_cache_ttl")]
+    cache_ttl: usize,
+    /// log_file specifies the path logs are appended to (stderr if omitted)
+    #[serde(rename = "log_file", alias = "debug_file", default)]
+    log_file: Option<String>,
+    /// log_level specifies one of off, error, warn, info, debug and trace
+    #[serde(rename = "log_level", default = "default_log_level")]
+    log_level: String,
+    /// log_format specifies 'text' or 'json'
+    #[serde(rename = "log_format", default = "default_log_format")]
+    log_format: String,
+}
+
+fn default_service_account_dir() -> String {
+    kube_crd::DEFAULT_SERVICE_ACCOUNT_DIR.to_string()
+}
+
+impl IPAMConfig {
+    fn get_networks(&self) -> Vec<String> {
+        if self.networks.is_empty() {
+            vec![self.network.clone()]
+        } else {
+            self.networks.clone()
+        }
+    }
+}
+
+fn default_daemon_socket() -> String {
+    daemon::DEFAULT_SOCKET.to_string()
+}
+
+fn default_cache_ttl() -> usize {
+    30
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_log_format() -> String {
+    "text".to_string()
 }
 
 #[derive(Deserialize, Debug)]
@@ -51,6 +121,137 @@ struct NetConf {
     ipam: IPAMConfig,
 }
 
+// kube clients and redis connections, which are kept across requests in daemon mode
+#[derive(Default)]
+struct Session {
+    clients: Mutex<HashMap<String, Client>>,
+    connections: Mutex<HashMap<String, Vec<redis::Connection>>>,
+}
+
+impl Session {
+    async fn get_client(&self, ipam: &IPAMConfig) -> Result<Client> {
+        let key = format!(
+            "{:?} {} {:?}",
+            ipam.kubeconfig, ipam.service_account_dir, ipam.kube_api_server
+        );
+        if let Some(client) = self.clients.lock().unwrap().get(&key) {
+            return Ok(client.clone());
+        }
+        let client = kube_crd::get_client(
+            ipam.kubeconfig.as_deref(),
+            &ipam.service_account_dir,
+            ipam.kube_api_server.as_deref(),
+        )
+        .await?;
+        self.clients.lock().unwrap().insert(key, client.clone());
+        Ok(client)
+    }
+
+    fn get_connection(&self, redis_ip: &str) -> redis::RedisResult<redis::Connection> {
+        if let Some(con) = self
+            .connections
+            .lock()
+            .unwrap()
+            .get_mut(redis_ip)
+            .and_then(|v| v.pop())
+        {
+            return Ok(con);
+        }
+        RedisClient::open(redis_ip)?.get_connection()
+    }
+
+    fn put_connection(&self, redis_ip: &str, con: redis::Connection) {
+        self.connections
+            .lock()
+            .unwrap()
+            .entry(redis_ip.to_string())
+            .or_default()
+            .push(con);
+    }
+}
+
+fn parse_netconf(stdin: &str) -> Result<NetConf> {
+    let mut netconf: NetConf = serde_json::from_str(stdin).map_err(|e| {
+        CNIError::new(ERR_DECODING_FAILURE, "failed to parse network configuration", &e.to_string())
+    })?;
+    if netconf.ipam.network.is_empty() && netconf.ipam.networks.is_empty() {
+        if netconf.ipam.ip_allocations.is_none() && netconf.ipam.spec_file.is_none() {
+            return Err(CNIError::new(
+                ERR_INVALID_NETWORK_CONFIG,
+                "one of network, networks, ipAllocations and spec_file is required",
+                "",
+            )
+            .into());
+        }
+        netconf.ipam.network = format!("static/{}", netconf.netconf.name);
+    }
+    Ok(netconf)
+}
+
+// admin subcommands, which operate on redis directly
+async fn run_subcommand(subcommand: &str, sub_matches: &ArgMatches) -> Result<String> {
+    let get_arg = |name: &str| -> Result<&String> {
+        sub_matches
+            .get_one::<String>(name)
+            .ok_or_else(|| anyhow::anyhow!("--{} is required", name))
+    };
+    let mut con = RedisClient::open(get_arg("redis")?.as_str())?.get_connection()?;
+    // default output format depends on the subcommand
+    let output = sub_matches.get_one::<String>("output").map(|s| s.as_str());
+
+    // restore does not require kubernetes, everything is in the dump
+    if subcommand == "restore" {
+        if let Some(output) = output {
+            return Err(anyhow::anyhow!("restore does not support output format {}", output));
+        }
+        let dump = backup::parse_dump(&std::fs::read_to_string(get_arg("file")?)?)?;
+        let (lines, conflicts) = backup::diff(&mut con, &dump)?;
+        if *sub_matches.get_one::<bool>("dry-run").unwrap() {
+            let mut output = lines;
+            if !conflicts.is_empty() {
+                output.push(format!("conflict (restore refused): {}", conflicts.join(", ")));
+            }
+            return Ok(output.join("\n"));
+        }
+        backup::restore(&mut con, &dump)?;
+        return Ok(format!("restored {} changes", lines.len()));
+    }
+
+    // operator's environment: KUBECONFIG, ~/.kube/config or in-cluster config
+    let client = match sub_matches.get_one::<String>("kubeconfig") {
+        Some(s) => kube_crd::get_client_kubeconfig(s).await?,
+        None => Client::try_default().await?,
+    };
+    if subcommand == "dump" {
+        let networkips = match sub_matches.get_many::<String>("networks") {
+            Some(networks) => {
+                let mut networkips = vec![];
+                for network in networks {
+                    networkips.push(kube_crd::get_crd(&client, network).await?);
+                }
+                networkips
+            }
+            None => kube_crd::list_crd(&client).await?,
+        };
+        let dump = backup::dump(&mut con, &networkips)?;
+        return backup::format_dump(&dump, output.unwrap_or("yaml").parse()?);
+    }
+
+    let format: admin::OutputFormat = output.unwrap_or("table").parse()?;
+    let networkip = kube_crd::get_crd(&client, get_arg("network")?).await?;
+    let ip = || -> Result<std::net::IpAddr> {
+        Ok(sub_matches.get_one::<String>("ip").unwrap().parse()?)
+    };
+    match subcommand {
+        "list" => admin::list(&mut con, &networkip, format),
+        "show" => admin::show(&mut con, &networkip, &ip()?, format),
+        "usage" => admin::usage(&mut con, &networkip, format),
+        "reserve" => admin::reserve(&mut con, &networkip, &ip()?, format),
+        "release" => admin::release(&mut con, &networkip, &ip()?, format),
+        c => Err(anyhow::anyhow!("unknown subcommand: {}", c)),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let create_arg: Arg = Arg::new("create")
@@ -60,20 +261,110 @@ async fn main() -> Result<()> {
         .required(false)
         .action(ArgAction::SetTrue);
     let kubeconfig_arg: Arg = Arg::new("kubeconfig")
-        .help("kubeconfig path")
+        .help("kubeconfig path (admin subcommands use default kubeconfig or in-cluster config if omitted)")
         .long("kubeconfig")
         .takes_value(true)
+        .global(true)
+        .required(false);
+
+    let redis_arg: Arg = Arg::new("redis")
+        .help("redis URL, e.g. redis://10.1.1.1/ (admin subcommands)")
+        .long("redis")
+        .takes_value(true)
+        .global(true)
         .required(false);
+    let network_arg: Arg = Arg::new("network")
+        .help("target networkip, <namespace>/<name> (admin subcommands)")
+        .long("network")
+        .takes_value(true)
+        .global(true)
+        .required(false);
+    let output_arg: Arg = Arg::new("output")
+        .help("output format: table (default) or json for admin subcommands, yaml (default) or json for dump")
+        .long("output")
+        .short('o')
+        .takes_value(true)
+        .global(true)
+        .value_parser(["table", "json", "yaml"])
+        .required(false);
+    let ip_arg = || Arg::new("ip").help("IP address").required(true);
 
     let app: App = App::new("differance-cni")
         .author("Tomofumi Hayashi")
         .version("0.1.0")
         .about("IPAM CNI plugin with redis backend")
         .arg(create_arg)
-        .arg(kubeconfig_arg);
+        .arg(kubeconfig_arg)
+        .arg(redis_arg)
+        .arg(network_arg)
+        .arg(output_arg)
+        .subcommand(App::new("list").about("list allocated addresses"))
+        .subcommand(App::new("show").about("show state of the address").arg(ip_arg()))
+        .subcommand(App::new("usage").about("show utilization of each allocation"))
+        .subcommand(App::new("reserve").about("reserve the address").arg(ip_arg()))
+        .subcommand(App::new("release").about("release the address").arg(ip_arg()))
+        .subcommand(
+            App::new("dump")
+                .about("dump allocation state as YAML (or JSON with '-o json')")
+                .arg(
+                    Arg::new("networks")
+                        .help("<namespace>/<name> of networkips (all networkips if omitted)")
+                        .multiple_values(true),
+                ),
+        )
+        .subcommand(
+            App::new("daemon")
+                .about("serve CNI requests over unix socket with warm connections")
+                .arg(
+                    Arg::new("socket")
+                        .help("unix socket path")
+                        .long("socket")
+                        .takes_value(true)
+                        .default_value(daemon::DEFAULT_SOCKET),
+                )
+                .arg(
+                    Arg::new("log-level")
+                        .help("log level (off, error, warn, info, debug, trace)")
+                        .long("log-level")
+                        .takes_value(true)
+                        .default_value("info"),
+                )
+                .arg(
+                    Arg::new("log-format")
+                        .help("log format")
+                        .long("log-format")
+                        .takes_value(true)
+                        .value_parser(["text", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .subcommand(
+            App::new("restore")
+                .about("restore allocation state from dump into empty redis")
+                .arg(Arg::new("file").help("dump file (YAML or JSON)").required(true))
+                .arg(
+                    Arg::new("dry-run")
+                        .help("show changes without writing redis")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue),
+                ),
+        );
 
     // check '--create' flag for CRDs initialization
     let matches = app.try_get_matches()?;
+    if let Some(("daemon", sub_matches)) = matches.subcommand() {
+        logging::init(
+            sub_matches.get_one::<String>("log-level").unwrap(),
+            sub_matches.get_one::<String>("log-format").unwrap().parse()?,
+            None,
+        )?;
+        return run_daemon(sub_matches.get_one::<String>("socket").unwrap()).await;
+    }
+    if let Some((subcommand, sub_matches)) = matches.subcommand() {
+        let output = run_subcommand(subcommand, sub_matches).await?;
+        println!("{}", output);
+        return Ok(());
+    }
     if *matches.get_one::<bool>("create").unwrap() {
         match matches.get_one::<String>("kubeconfig") {
             Some(s) => {
@@ -86,132 +377,606 @@ async fn main() -> Result<()> {
         return Ok(());
     };
 
+    // log configuration is in netconf, so errors before that go to stderr
     let (command, cmd_args) = match get_cmdargs() {
         Ok(v) => v,
         Err(e) => {
-            println!("Error: {}", e);
-            return Err(e.into());
+            let _ = logging::init("info", logging::LogFormat::Text, None);
+            error!("failed to get CNI arguments: {}", e);
+            let code = match e {
+                CmdArgsError::FailedReadStdIn(_) => ERR_IO_FAILURE,
+                _ => ERR_INVALID_ENVIRONMENT_VARIABLES,
+            };
+            exit_with_cni_error(&CNIError::new(code, &e.to_string(), ""));
         }
     };
 
-    let netconf: NetConf = match serde_json::from_str(cmd_args.stdin_data.as_str()) {
+    let info = PluginInfo::all();
+    if command == "VERSION" {
+        match info.get_version_output(&cmd_args.stdin_data) {
+            Ok(output) => println!("{}", output),
+            Err(err) => exit_with_cni_error(&err),
+        }
+        return Ok(());
+    }
+
+    let netconf = match parse_netconf(&cmd_args.stdin_data) {
         Ok(v) => v,
         Err(err) => {
-            println!("failed to : {}", err);
-            return Err(err.into());
+            let _ = logging::init("info", logging::LogFormat::Text, None);
+            error!("failed to parse netconf: {}", err);
+            exit_with_cni_error(&to_cni_error(err, ""));
         }
     };
-    let mut file = File::options().create(true).append(true).open(netconf.ipam.debug_file)?;
+    let cni_version = netconf.netconf.cni_version.as_str();
+    let log_format = netconf.ipam.log_format.parse();
+    if let Err(err) = log_format.and_then(|format| {
+        logging::init(&netconf.ipam.log_level, format, netconf.ipam.log_file.as_deref())
+    }) {
+        let _ = logging::init("info", logging::LogFormat::Text, None);
+        error!("failed to initialize logging: {}", err);
+        exit_with_cni_error(&CNIError {
+            cni_version: cni_version.to_string(),
+            ..CNIError::new(ERR_INVALID_NETWORK_CONFIG, &err.to_string(), "")
+        });
+    }
+    if let Err(err) = info.check_compatible(&command, &netconf.netconf) {
+        error!("{}", err);
+        exit_with_cni_error(&err);
+    }
 
-    // read kubeconfig
-    let config = Config::from_custom_kubeconfig(
-        Kubeconfig::read_from(&netconf.ipam.kubeconfig)?,
-        &KubeConfigOptions::default(),
-    )
-    .await?;
-    let client = Client::try_from(config)?;
+    let forwarded = if Path::new(&netconf.ipam.daemon_socket).exists() {
+        debug!(socket = netconf.ipam.daemon_socket.as_str(), "forwarding to daemon");
+        let request = daemon::Request::from_env(cmd_args.stdin_data.clone());
+        daemon::forward(&netconf.ipam.daemon_socket, &request).await.transpose()
+    } else {
+        None
+    };
+    let output = match forwarded {
+        Some(Ok(daemon::Response { error: Some(e), code, .. })) => Err(CNIError::new(
+            code.unwrap_or(ERR_INTERNAL),
+            &e,
+            "",
+        )
+        .into()),
+        Some(Ok(response)) => Ok(response.output),
+        Some(Err(e)) => {
+            error!("failed to forward to daemon: {}", e);
+            Err(anyhow::anyhow!("failed to forward to daemon: {}", e))
+        }
+        // no daemon, run in this process
+        None => run_cni(&Session::default(), command.as_str(), &cmd_args, &netconf).await,
+    };
+    match output {
+        Ok(output) if output.is_empty() => {}
+        Ok(output) => println!("{}", output),
+        // already logged by run_cni (or by the daemon)
+        Err(err) => exit_with_cni_error(&to_cni_error(err, cni_version)),
+    }
+    Ok(())
+}
+
+// print the error in CNI format and exit, for errors which the runtime handles
+fn exit_with_cni_error(err: &CNIError) -> ! {
+    println!("{}", serde_json::to_string(err).unwrap_or_default());
+    std::process::exit(1);
+}
+
+// serve CNI requests forwarded by the plugin
+async fn run_daemon(socket: &str) -> Result<()> {
+    let session = Arc::new(Session::default());
+    daemon::serve(socket, move |request: daemon::Request| {
+        let session = session.clone();
+        async move {
+            // redis::Connection blocks, so each request runs on a blocking thread
+            // rather than on a runtime worker
+            let handle = tokio::runtime::Handle::current();
+            tokio::task::spawn_blocking(move || {
+                let (command, cmd_args) = get_cmdargs_vars(&request.env, request.stdin)?;
+                let netconf = parse_netconf(&cmd_args.stdin_data)?;
+                handle.block_on(run_cni(&session, command.as_str(), &cmd_args, &netconf))
+            })
+            .await?
+        }
+    })
+    .await
+}
+
+// run the CNI command and returns its output
+async fn run_cni(session: &Session, command: &str, cmd_args: &CmdArgs, netconf: &NetConf) -> Result<String> {
+    let span = tracing::info_span!(
+        "cni",
+        command,
+        container_id = cmd_args.container_id.as_str(),
+        netns = cmd_args.netns.as_str(),
+        ifname = cmd_args.ifname.as_str(),
+    );
+    async {
+        let mut con = match session.get_connection(&netconf.ipam.redis_ip) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to connect redis: {}", e);
+                return Err(match command {
+                    "STATUS" => CNIError::new(ERR_PLUGIN_NOT_AVAILABLE, "failed to connect redis", &e.to_string()).into(),
+                    _ => e.into(),
+                });
+            }
+        };
+        let result = cmd_main(session, &mut con, command, cmd_args, netconf).await;
+        match &result {
+            Ok(_) => info!("done"),
+            Err(e) => error!("failed: {}", e),
+        }
+        // metrics are best-effort
+        if let Err(e) = metrics::record_command(&mut con, command, result.is_ok()) {
+            warn!("failed to record metrics: {}", e);
+        }
+        // failed command may leave WATCH on the connection. Reuse the connection
+        // only if it is cleared, and the connection looks healthy.
+        match redis::cmd("UNWATCH").query::<()>(&mut con) {
+            Ok(_) => session.put_connection(&netconf.ipam.redis_ip, con),
+            Err(e) => warn!("dropping redis connection: {}", e),
+        }
+        result
+    }
+    .instrument(span)
+    .await
+}
+
+fn get_node_name(netconf: &NetConf) -> Result<String> {
+    match &netconf.ipam.node_name {
+        Some(v) => Ok(v.clone()),
+        None => Ok(std::fs::read_to_string("/proc/sys/kernel/hostname")?.trim().to_string()),
+    }
+}
+
+// static pool defined in netconf or in spec_file, which does not need kubernetes
+fn get_static_networkip(netconf: &NetConf) -> Result<Option<NetworkIP>> {
+    let spec = match (&netconf.ipam.ip_allocations, &netconf.ipam.spec_file) {
+        (Some(ip_allocations), _) => NetworkIPSpec {
+            ip_allocations: ip_allocations.clone(),
+            dns: netconf.ipam.dns.clone(),
+            allowed_namespaces: vec![],
+            namespace_selector: None,
+        },
+        (None, Some(path)) => kube_crd::parse_spec(&std::fs::read_to_string(path)?)?,
+        (None, None) => return Ok(None),
+    };
+    let (namespace, name) = kube_crd::parse_namespacedname(&netconf.ipam.network)?;
+    let mut networkip = NetworkIP::new(name, spec);
+    networkip.metadata.namespace = Some(namespace.to_string());
+    Ok(Some(networkip))
+}
+
+// read networkip from the cache in redis, or from kubernetes on miss. The cache
+// is refreshed by differance-controller when the object is updated.
+async fn get_networkip(
+    client: &Client,
+    con: &mut redis::Connection,
+    netconf: &NetConf,
+    network: &str,
+) -> Result<NetworkIP> {
+    if netconf.ipam.cache_ttl > 0 {
+        match redisdb::get_cached_networkip(con, network) {
+            Ok(Some(networkip)) => {
+                debug!(network, "networkip loaded from cache");
+                return Ok(networkip);
+            }
+            Ok(None) => {}
+            Err(e) => warn!(network, "failed to read networkip cache: {}", e),
+        }
+    }
 
     // if no target version CRD, then show error message!
-    if !(kube_crd::check_crd(&client).await) {
+    if !(kube_crd::check_crd(client).await) {
         return Err(anyhow::anyhow!("no CRD {} found", kube_crd::CRD_NAME));
     };
+    let networkip = kube_crd::get_crd(client, network).await?;
+    if netconf.ipam.cache_ttl > 0 {
+        if let Err(e) = redisdb::set_cached_networkip(con, &networkip, netconf.ipam.cache_ttl) {
+            warn!(network, "failed to cache networkip: {}", e);
+        }
+    }
+    Ok(networkip)
+}
+
+fn get_network_name(networkip: &NetworkIP) -> String {
+    format!(
+        "{}/{}",
+        networkip.metadata.namespace.clone().unwrap_or_default(),
+        networkip.metadata.name.clone().unwrap_or_default()
+    )
+}
+
+fn split_networks(networks: &str) -> Vec<String> {
+    networks
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+// networkips from kubernetes. DEL prefers the spec stored in redis, and skips
+// networks which have no pool.
+async fn get_kube_networkips(
+    session: &Session,
+    command: &str,
+    cmd_args: &CmdArgs,
+    k8s_args: &K8sArgs,
+    con: &mut redis::Connection,
+    netconf: &NetConf,
+) -> Result<(Vec<NetworkIP>, Option<Client>)> {
+    let client = session.get_client(&netconf.ipam).await;
+    // ADD chooses networkips by pod annotation. Others use the ones chosen in ADD,
+    // because the pod may be already gone.
+    let networks = match command {
+        "ADD" => match (
+            &client,
+            &k8s_args.k8s_pod_namespace,
+            &k8s_args.k8s_pod_name,
+        ) {
+            (Ok(client), Some(namespace), Some(name)) => match kube_crd::get_pod_networks(client, namespace, name).await {
+                Ok(v) => v.filter(|v| !v.is_empty()).unwrap_or_else(|| netconf.ipam.get_networks()),
+                Err(e) => {
+                    warn!("failed to get pod annotation, using networks in netconf: {}", e);
+                    netconf.ipam.get_networks()
+                }
+            },
+            _ => netconf.ipam.get_networks(),
+        },
+        _ => match redisdb::get_container_network(con, &cmd_args.container_id, &cmd_args.ifname, &netconf.netconf.name)? {
+            Some(v) => split_networks(&v),
+            None => netconf.ipam.get_networks(),
+        },
+    };
+    debug!(networks = ?networks, "networkips selected");
+
+    let mut networkips = vec![];
+    for network in networks.iter() {
+        // DEL should do its best to clean up even if API server is unreachable, and
+        // releasing addresses only needs the pool layout, which is stored in redis
+        if command == "DEL" {
+            if let Some(v) = redisdb::get_stored_networkip(con, network)? {
+                networkips.push(v);
+                continue;
+            }
+        }
+        let networkip = match &client {
+            Ok(client) => get_networkip(client, con, netconf, network).await,
+            Err(e) => Err(anyhow::anyhow!("failed to create kube client: {}", e)),
+        };
+        match (command, networkip) {
+            (_, Ok(v)) => networkips.push(v),
+            // no pool, nothing to release
+            ("DEL", Err(e)) => warn!(network = network.as_str(), "{}, no spec stored in redis", e),
+            (_, Err(e)) => return Err(e),
+        }
+    }
+    Ok((networkips, client.ok()))
+}
+
+// prepare the pool in redis for allocation
+fn init_pool(con: &mut redis::Connection, networkip: &NetworkIP) -> Result<()> {
+    // create redis DB if not exist
+    match redisdb::check_network_bitmap(con, networkip)? {
+        0 => redisdb::create_network_bitmap(con, networkip)?,
+        n => {
+            if networkip.spec.ip_allocations.len() != n.unsigned_abs() {
+                return Err(anyhow::anyhow!("database mismatch happen"));
+            }
+            // keep the stored spec up to date for offline DEL. Excludes may be
+            // updated as well, and static pools have no controller to mark them.
+            if !redisdb::check_network_spec(con, networkip)? {
+                redisdb::set_network_spec(con, networkip)?;
+                redisdb::mark_excluded_ips(con, networkip)?;
+            }
+        }
+    };
+    Ok(())
+}
+
+fn allocate_ip(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    alloc: &NetworkIPAllocations,
+    node_name: &str,
+    cmd_args: &CmdArgs,
+    k8s_args: &K8sArgs,
+) -> redis::RedisResult<IpAddr> {
+    let ip = match alloc.block_size {
+        Some(block_size) => redisdb::get_block_available_ip(con, networkip, alloc, block_size, node_name)?,
+        None => redisdb::get_first_available_ip(con, networkip, alloc)?,
+    };
+    let pod_info = redisdb::PodInformation {
+        namespace: k8s_args.k8s_pod_namespace.as_deref().unwrap_or("UnknownNamespace"),
+        name: k8s_args.k8s_pod_name.as_deref().unwrap_or("UnknownPodName"),
+        container_id: get_owner_container_id(cmd_args, k8s_args),
+        uid: k8s_args.k8s_pod_uid.as_deref(),
+    };
+    let _ = redisdb::add_pod_information(con, networkip, alloc, &ip, pod_info.to_string());
+    Ok(ip)
+}
+
+// container id recorded as the owner of addresses. Sandbox (infra) container id is
+// preferred, and DEL must compare with the same one.
+fn get_owner_container_id<'a>(cmd_args: &'a CmdArgs, k8s_args: &'a K8sArgs) -> &'a str {
+    k8s_args.k8s_pod_infra_container_id.as_deref().unwrap_or(&cmd_args.container_id)
+}
+
+// prevResult from the runtime, as the latest version
+fn get_prev_result(netconf: &NetConf) -> Result<CNI110Result> {
+    netconf.netconf.get_current_result().map_err(|e| {
+        CNIError::new(ERR_DECODING_FAILURE, "failed to parse prevResult", &e.to_string()).into()
+    })
+}
+
+fn release_ip(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    alloc: &NetworkIPAllocations,
+    ip: &IpAddr,
+) {
+    let _ = redisdb::del_pod_information(con, networkip, alloc, ip);
+    let _ = redisdb::return_ip(con, networkip, alloc, *ip);
+    info!(ip = %ip, "released");
+}
+
+fn release_ips(con: &mut redis::Connection, allocated: &[(&NetworkIP, &NetworkIPAllocations, IpAddr)]) {
+    for (networkip, alloc, ip) in allocated.iter() {
+        release_ip(con, networkip, alloc, ip);
+    }
+}
+
+// ADD fails on unknown CNI_ARGS keys unless 'IgnoreUnknown=1' is given (as golang
+// CNI does), but invalid CNI_ARGS should not block cleanup
+fn load_k8s_args(command: &str, cmd_args: &CmdArgs) -> Result<K8sArgs> {
+    match cmd_args.load_args() {
+        Ok(v) => Ok(v),
+        Err(e) if command == "ADD" => Err(CNIError::new(
+            ERR_INVALID_ENVIRONMENT_VARIABLES,
+            "invalid CNI_ARGS",
+            &e.to_string(),
+        )
+        .into()),
+        Err(e) => {
+            warn!("ignoring CNI_ARGS: {}", e);
+            Ok(K8sArgs::default())
+        }
+    }
+}
+
+#[test]
+fn test_load_k8s_args() {
+    let cmd_args = CmdArgs {
+        container_id: "0123abcd".to_string(),
+        netns: String::new(),
+        ifname: "net1".to_string(),
+        args: HashMap::new(),
+        path: String::new(),
+        stdin_data: String::new(),
+        args_data: "K8S_POD_NAMESPACE=default;K8S_POD_NAME=pod1;FOO=bar".to_string(),
+    };
+    assert!(load_k8s_args("ADD", &cmd_args).is_err());
+    // DEL goes on to release addresses with the ones recorded in ADD
+    let k8s_args = load_k8s_args("DEL", &cmd_args).unwrap();
+    assert!(k8s_args.k8s_pod_name.is_none());
+    assert_eq!(get_owner_container_id(&cmd_args, &k8s_args), "0123abcd");
 
-    // read crds
-    let networkip = kube_crd::get_crd(&client, netconf.ipam.network.as_str()).await?;
-    //eprintln!("testoutput!: {:?}", networkip);
+    let cmd_args = CmdArgs {
+        args_data: format!("IgnoreUnknown=1;{}", cmd_args.args_data),
+        ..cmd_args
+    };
+    let k8s_args = load_k8s_args("ADD", &cmd_args).unwrap();
+    assert_eq!(k8s_args.k8s_pod_name.as_deref(), Some("pod1"));
+}
 
-    match command.as_str() {
+// plugin is available as long as redis is reachable
+fn cmd_status(con: &mut redis::Connection) -> Result<String> {
+    match redis::cmd("PING").query::<()>(con) {
+        Ok(_) => Ok(String::new()),
+        Err(e) => Err(CNIError::new(ERR_PLUGIN_NOT_AVAILABLE, "redis is not reachable", &e.to_string()).into()),
+    }
+}
+
+// release addresses of the attachments which are not in 'cni.dev/valid-attachments',
+// i.e. the ones whose DEL was lost
+fn cmd_gc(con: &mut redis::Connection, netconf: &NetConf) -> Result<String> {
+    let valid: HashSet<(&str, &str)> = netconf
+        .netconf
+        .valid_attachments
+        .iter()
+        .map(|a| (a.container_id.as_str(), a.ifname.as_str()))
+        .collect();
+    let valid_containers: HashSet<&str> = valid.iter().map(|(container_id, _)| *container_id).collect();
+    // stale containers by network, to scan each pool once
+    let mut stale: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut records = vec![];
+    for record in redisdb::get_container_networks(con)? {
+        if record.netconf_name != netconf.netconf.name
+            || valid.contains(&(record.container_id.as_str(), record.ifname.as_str()))
+        {
+            continue;
+        }
+        // other attachments of the container may share the pool, so its addresses
+        // are kept while the container is valid
+        if !valid_containers.contains(record.container_id.as_str()) {
+            for network in split_networks(&record.network) {
+                stale.entry(network).or_default().insert(record.container_id.clone());
+            }
+        }
+        records.push((record.container_id, record.ifname));
+    }
+    for (network, containers) in stale.iter() {
+        let networkip = match redisdb::get_stored_networkip(con, network)? {
+            Some(v) => v,
+            None => continue,
+        };
+        for alloc in networkip.spec.ip_allocations.iter() {
+            for (ip, pod_info) in redisdb::get_pod_informations(con, &networkip, alloc)? {
+                let owner = redisdb::PodInformation::parse(&pod_info).map(|i| i.container_id);
+                if owner.is_some_and(|owner| containers.contains(owner)) {
+                    release_ip(con, &networkip, alloc, &ip);
+                }
+            }
+        }
+    }
+    for (container_id, ifname) in records.iter() {
+        redisdb::del_container_network(con, container_id, ifname, &netconf.netconf.name)?;
+    }
+    info!(attachments = records.len(), "garbage collected");
+    Ok(String::new())
+}
+
+async fn cmd_main(
+    session: &Session,
+    con: &mut redis::Connection,
+    command: &str,
+    cmd_args: &CmdArgs,
+    netconf: &NetConf,
+) -> Result<String> {
+    // not about an attachment, so no CNI_ARGS nor networkips
+    match command {
+        "STATUS" => return cmd_status(con),
+        "GC" => return cmd_gc(con, netconf),
+        _ => {}
+    }
+    let k8s_args = load_k8s_args(command, cmd_args)?;
+    let (networkips, client) = match get_static_networkip(netconf)? {
+        Some(networkip) => (vec![networkip], None),
+        None => get_kube_networkips(session, command, cmd_args, &k8s_args, con, netconf).await?,
+    };
+    // only ADD, which creates pools, needs a valid spec. Others must work with
+    // pools created by older versions
+    for networkip in networkips.iter().filter(|_| command == "ADD") {
+        let errors = validation::validate_spec(&networkip.spec);
+        if !errors.is_empty() {
+            return Err(anyhow::anyhow!(
+                "invalid networkip {}: {}",
+                get_network_name(networkip),
+                validation::join_errors(&errors)
+            ));
+        }
+    }
+    let networks: Vec<String> = networkips.iter().map(get_network_name).collect();
+    debug!(networks = ?networks, "networkips loaded");
+
+    match command {
         "ADD" => {
-            let redis_client = RedisClient::open(netconf.ipam.redis_ip)?;
-            let mut con = redis_client.get_connection()?;
-            // checck redis DB
-            let key_exists = match redisdb::check_network_bitmap(&mut con, &networkip) {
-                Ok(v) => v,
-                Err(err) => return Err(err.into()),
+            // parsed ahead of allocation, not to leak addresses on invalid prevResult
+            let prev_result = get_prev_result(netconf)?;
+            for networkip in networkips.iter() {
+                kube_crd::check_namespace_allowed(
+                    client.as_ref(),
+                    networkip,
+                    k8s_args.k8s_pod_namespace.as_deref(),
+                )
+                .await?;
+            }
+            // recorded ahead of allocation, so that DEL after failed ADD finds the pools
+            redisdb::set_container_network(
+                con,
+                &cmd_args.container_id,
+                &cmd_args.ifname,
+                &netconf.netconf.name,
+                &networks.join(","),
+            )?;
+            for networkip in networkips.iter() {
+                init_pool(con, networkip)?;
+            }
+            let node_name = match networkips
+                .iter()
+                .flat_map(|n| n.spec.ip_allocations.iter())
+                .any(|a| a.block_size.is_some())
+            {
+                true => get_node_name(netconf)?,
+                false => String::new(),
             };
-            // create redis DB if not exist
-            match key_exists {
-                0 => redisdb::create_network_bitmap(&mut con, &networkip)?,
-                n => {
-                    if networkip.spec.ip_allocations.len() != n.unsigned_abs() {
-                        return Err(anyhow::anyhow!("database mismatch happen"));
+
+            let alloc_start = Instant::now();
+            let mut allocated: Vec<(&NetworkIP, &NetworkIPAllocations, IpAddr)> = vec![];
+            for networkip in networkips.iter() {
+                for alloc in networkip.spec.ip_allocations.iter() {
+                    match allocate_ip(con, networkip, alloc, &node_name, cmd_args, &k8s_args) {
+                        Ok(ip) => allocated.push((networkip, alloc, ip)),
+                        Err(e) => {
+                            // all or nothing, roll back addresses claimed so far
+                            release_ips(con, &allocated);
+                            return Err(anyhow::anyhow!("failed to allocate from {}: {}", alloc.name, e));
+                        }
                     }
                 }
-            };
-            let prev_result = netconf.netconf.get_current_result().unwrap();
-            let result = CNI100Result {
+            }
+            let _ = metrics::record_allocation_duration(con, alloc_start.elapsed());
+
+            // the interface in prevResult which the addresses belong to
+            let interface = prev_result.get_interface_index(&cmd_args.ifname, &cmd_args.netns);
+            let result = CNI110Result {
                 cni_version: prev_result.cni_version,
                 interfaces: prev_result.interfaces,
-                ips: networkip.spec.ip_allocations.iter().filter_map(|alloc| {
+                ips: allocated.iter().map(|(_, alloc, ip)| {
+                    info!(ip = %ip, "allocated");
                     let subnet: IPNet = alloc.subnet.parse().unwrap();
-                    let address = IPNet{
-                        ip: redisdb::get_first_available_ip(&mut con, &networkip, &alloc).unwrap(),
-                        netmask_len: subnet.netmask_len,
-                    };
-                    let _ = redisdb::add_pod_information(
-                        &mut con, &networkip, &alloc, 
-                        &address.ip,
-                        format!("{}/{} {}", 
-                                match cmd_args.args.get(&"K8S_POD_NAMESPACE".to_string()) {
-                                    Some(v) => v,
-                                    None => "UnknownNamespace",
-                                },
-                                match cmd_args.args.get(&"K8S_POD_NAME".to_string()) {
-                                    Some(v) => v,
-                                    None => "UnknownPodName",
-                                },
-                                match cmd_args.args.get(&"K8S_POD_INFRA_CONTAINER_ID".to_string()) {
-                                    Some(v) => v,
-                                    None => "UnknownContainerID",
-                                }));
-                    Some(CNI100IPAddress {
-                        interface: None,
-                        address: address,
+                    CNI110IPAddress {
+                        interface,
+                        address: IPNet{
+                            ip: *ip,
+                            netmask_len: subnet.netmask_len,
+                        },
                         gateway: alloc.gateway,
-                    })
-                }).collect::<Vec<CNI100IPAddress>>(),
-                routes: networkip.spec.ip_allocations
-                    .iter().map(|alloc| alloc.get_cni_route()).flatten().collect(),
-                    dns: DNS{
-                        nameservers: vec![],
-                        domain: "".to_string(),
-                        search: vec![],
-                        options: vec![],
-                    },
+                    }
+                }).collect(),
+                routes: networkips.iter()
+                    .flat_map(|n| n.spec.ip_allocations.iter())
+                    .flat_map(|alloc| alloc.get_cni_route()).collect(),
+                // earlier networkip takes precedence
+                dns: networkips.iter().rev().fold(netconf.netconf.dns.clone(), |dns, n| {
+                    n.spec.dns.get_cni_dns(&dns)
+                }),
             };
-            // K8S_POD_NAME, K8S_POD_NAMESPACE, K8S_POD_INFRA_CONTAINER_ID, K8S_POD_UID
-            println!("{}", netconf.netconf.get_result_output(&result).unwrap());
+            match netconf.netconf.get_result_output(&result) {
+                Ok(v) => Ok(v),
+                Err(e) => {
+                    release_ips(con, &allocated);
+                    Err(e.into())
+                }
+            }
         },
         "CHECK" => {
             // XXX: implement check!
-            let result = netconf.netconf.get_current_result().unwrap();
-            println!("{}", netconf.netconf.get_result_output(&result).unwrap());
+            let result = get_prev_result(netconf)?;
+            Ok(netconf.netconf.get_result_output(&result)?)
         },
         "DEL" => {
-            let result = netconf.netconf.get_current_result().unwrap();
-            let redis_client = RedisClient::open(netconf.ipam.redis_ip)?;
-            let mut con = redis_client.get_connection()?;
-            let _ = match redisdb::check_network_bitmap(&mut con, &networkip) {
-                Ok(v) => v,
-                Err(err) => return Err(err.into()),
-            };
+            let result = get_prev_result(netconf)?;
             for ip in result.ips.iter() {
                 let network_ip = ip.address.get_network_ip();
-                match networkip.spec.ip_allocations.iter().find(|x| x.get_network_ip() == network_ip) {
-                    Some(alloc) => {
-                        let _ = redisdb::del_pod_information(&mut con, &networkip, &alloc, &ip.address.ip);
-                        let _ = redisdb::return_ip(&mut con, &networkip, &alloc, ip.address.ip);
+                let found = networkips.iter().find_map(|networkip| {
+                    networkip.spec.ip_allocations.iter()
+                        .find(|x| x.get_network_ip() == network_ip)
+                        .map(|alloc| (networkip, alloc))
+                });
+                match found {
+                    Some((networkip, alloc)) => {
+                        // the address may be already reclaimed by controller and assigned to
+                        // another container, so release it only if we still own it
+                        if let Ok(Some(pod_info)) = redisdb::get_pod_information(con, networkip, alloc, &ip.address.ip) {
+                            let owner = redisdb::PodInformation::parse(&pod_info).map(|i| i.container_id);
+                            if owner != Some(get_owner_container_id(cmd_args, &k8s_args)) {
+                                debug!(ip = %ip.address.ip, owner = pod_info.as_str(), "owned by another container, skip");
+                                continue;
+                            }
+                        }
+                        release_ip(con, networkip, alloc, &ip.address.ip);
                     },
                     None => {
-                        eprintln!("not found!")
+                        warn!(ip = %ip.address.ip, "no allocation found for the address");
                     }
                 }
             }
+            let _ = redisdb::del_container_network(con, &cmd_args.container_id, &cmd_args.ifname, &netconf.netconf.name);
+            Ok(String::new())
         },
-        c => {
-            println!("unknown command: {}", c);
-        },
-    };
-    file.flush()?;
-    Ok(())
+        c => Err(anyhow::anyhow!("unknown command: {}", c)),
+    }
 }
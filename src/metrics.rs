@@ -0,0 +1,209 @@
+// metrics.rs: prometheus metrics for pool utilization and CNI command outcome
+//
+// CNI plugin is a short-lived process, so it records counters into redis and
+// the exporter (in differance-controller) renders them with pool utilization.
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use kube::Client;
+use redis::Client as RedisClient;
+use redis::Commands;
+use tracing::{info, warn};
+
+use crate::kube_crd::{self, NetworkIP, NetworkIPAllocationStatus, NetworkIPStatus};
+use crate::redisdb;
+
+const COMMANDS_KEY: &str = "metrics:commands";
+const ALLOCATION_DURATION_KEY: &str = "metrics:allocation_duration";
+
+pub const ALLOCATION_DURATION_BUCKETS: [f64; 11] =
+    [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub fn record_command(con: &mut redis::Connection, command: &str, success: bool) -> redis::RedisResult<()> {
+    let field = format!("{}/{}", command, if success { "success" } else { "error" });
+    con.hincr(redisdb::get_global_key_name(COMMANDS_KEY), field, 1)
+}
+
+pub fn record_allocation_duration(con: &mut redis::Connection, duration: Duration) -> redis::RedisResult<()> {
+    let seconds = duration.as_secs_f64();
+    let key = redisdb::get_global_key_name(ALLOCATION_DURATION_KEY);
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for bucket in ALLOCATION_DURATION_BUCKETS.iter().filter(|b| seconds <= **b) {
+        pipe.hincr(&key, format!("le/{}", bucket), 1).ignore();
+    }
+    pipe.hincr(&key, "le/+Inf", 1)
+        .ignore()
+        .hincr(&key, "count", 1)
+        .ignore()
+        .hincr(&key, "sum", seconds)
+        .ignore();
+    pipe.query(con)
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+type AllocationGauge = (&'static str, &'static str, fn(&NetworkIPAllocationStatus) -> u64);
+
+fn render_pool_metrics(out: &mut String, pools: &[(NetworkIP, NetworkIPStatus)]) {
+    let gauges: [AllocationGauge; 4] = [
+        ("differance_pool_capacity_addresses", "Number of addresses in the allocation range.", |a| a.capacity),
+        ("differance_pool_allocated_addresses", "Number of addresses assigned to pods or reserved.", |a| a.allocated),
+        ("differance_pool_free_addresses", "Number of addresses available for allocation.", |a| a.free),
+        ("differance_pool_excluded_addresses", "Number of addresses excluded from allocation.", |a| a.excluded),
+    ];
+    for (name, help, value) in gauges.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (networkip, status) in pools {
+            for alloc in status.allocations.iter() {
+                let _ = writeln!(
+                    out,
+                    "{}{{namespace=\"{}\",networkip=\"{}\",allocation=\"{}\"}} {}",
+                    name,
+                    escape_label(networkip.metadata.namespace.as_deref().unwrap_or_default()),
+                    escape_label(networkip.metadata.name.as_deref().unwrap_or_default()),
+                    escape_label(&alloc.name),
+                    value(alloc)
+                );
+            }
+        }
+    }
+}
+
+fn render_command_metrics(out: &mut String, commands: &[(String, u64)]) {
+    let _ = writeln!(out, "# HELP differance_cni_commands_total Number of CNI commands by outcome.");
+    let _ = writeln!(out, "# TYPE differance_cni_commands_total counter");
+    for (field, count) in commands {
+        if let Some((command, result)) = field.split_once('/') {
+            let _ = writeln!(
+                out,
+                "differance_cni_commands_total{{command=\"{}\",result=\"{}\"}} {}",
+                escape_label(command),
+                escape_label(result),
+                count
+            );
+        }
+    }
+}
+
+fn render_allocation_duration(out: &mut String, histogram: &std::collections::HashMap<String, String>) {
+    let get = |field: &str| histogram.get(field).map(|s| s.as_str()).unwrap_or("0");
+    let name = "differance_allocation_duration_seconds";
+    let _ = writeln!(out, "# HELP {} Time to allocate addresses in ADD.", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for bucket in ALLOCATION_DURATION_BUCKETS.iter() {
+        let le = bucket.to_string();
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, get(&format!("le/{}", le)));
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, get("le/+Inf"));
+    let _ = writeln!(out, "{}_sum {}", name, get("sum"));
+    let _ = writeln!(out, "{}_count {}", name, get("count"));
+}
+
+#[test]
+fn test_render_command_metrics() {
+    let mut out = String::new();
+    render_command_metrics(
+        &mut out,
+        &[("ADD/success".to_string(), 3), ("DEL/error".to_string(), 1)],
+    );
+    assert_eq!(
+        out,
+        "# HELP differance_cni_commands_total Number of CNI commands by outcome.\n\
+         # TYPE differance_cni_commands_total counter\n\
+         differance_cni_commands_total{command=\"ADD\",result=\"success\"} 3\n\
+         differance_cni_commands_total{command=\"DEL\",result=\"error\"} 1\n"
+    );
+}
+
+#[test]
+fn test_render_allocation_duration() {
+    let histogram = [("le/0.005", "1"), ("le/+Inf", "2"), ("count", "2"), ("sum", "0.5")]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let mut out = String::new();
+    render_allocation_duration(&mut out, &histogram);
+    assert!(out.contains("differance_allocation_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+    assert!(out.contains("differance_allocation_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+    assert!(out.contains("differance_allocation_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+    assert!(out.contains("differance_allocation_duration_seconds_count 2\n"));
+}
+
+pub async fn render(client: &Client, redis_client: &RedisClient) -> Result<String> {
+    let networkips = kube_crd::list_crd(client).await?;
+    let redis_client = redis_client.clone();
+    // redis::Connection blocks, so keep it off the runtime workers
+    tokio::task::spawn_blocking(move || render_redis(&redis_client, networkips)).await?
+}
+
+fn render_redis(redis_client: &RedisClient, networkips: Vec<NetworkIP>) -> Result<String> {
+    let mut con = redis_client.get_connection()?;
+
+    let mut pools = vec![];
+    for networkip in networkips {
+        match redisdb::get_network_status(&mut con, &networkip) {
+            Ok(status) => pools.push((networkip, status)),
+            Err(e) => warn!(name = ?networkip.metadata.name, "failed to get pool status: {}", e),
+        }
+    }
+    let mut commands: Vec<(String, u64)> = con.hgetall(redisdb::get_global_key_name(COMMANDS_KEY))?;
+    commands.sort();
+    let histogram: std::collections::HashMap<String, String> =
+        con.hgetall(redisdb::get_global_key_name(ALLOCATION_DURATION_KEY))?;
+
+    let mut out = String::new();
+    render_pool_metrics(&mut out, &pools);
+    render_command_metrics(&mut out, &commands);
+    render_allocation_duration(&mut out, &histogram);
+    Ok(out)
+}
+
+async fn handle(req: Request<Body>, client: Client, redis_client: RedisClient) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap());
+    }
+    Ok(match render(&client, &redis_client).await {
+        Ok(body) => Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => {
+            warn!("failed to render metrics: {}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(e.to_string()))
+                .unwrap()
+        }
+    })
+}
+
+// serve '/metrics' in plain http
+pub async fn serve(addr: SocketAddr, client: Client, redis_client: RedisClient) -> Result<()> {
+    let make_svc = make_service_fn(move |_conn| {
+        let client = client.clone();
+        let redis_client = redis_client.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(req, client.clone(), redis_client.clone())
+            }))
+        }
+    });
+    info!("serving metrics on {}", addr);
+    Server::try_bind(&addr)?.serve(make_svc).await?;
+    Ok(())
+}
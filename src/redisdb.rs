@@ -1,4 +1,5 @@
 use anyhow::Result; // bail may be used.
+use std::collections::BTreeMap;
 use std::net::IpAddr;
 use std::net::IpAddr::V4;
 use std::net::IpAddr::V6;
@@ -57,7 +58,7 @@ fn get_bitmap_key_name(networkip: &NetworkIP, alloc_name: &str) -> String {
             "{}/{}/{}/bitmap",
             networkip_namespace,
             networkip_name,
-            alloc_name.clone()
+            alloc_name
         )
 }
 
@@ -69,10 +70,27 @@ fn get_baseip_key_name(networkip: &NetworkIP, alloc_name: &str) -> String {
             "{}/{}/{}/baseip",
             networkip_namespace,
             networkip_name,
-            alloc_name.clone()
+            alloc_name
         )
 }
 
+fn get_blocks_key_name(networkip: &NetworkIP, alloc_name: &str) -> String {
+    format!(
+        "{}/{}/{}/blocks",
+        networkip.metadata.namespace.clone().unwrap(),
+        networkip.metadata.name.clone().unwrap(),
+        alloc_name
+    )
+}
+
+fn get_spec_key_name(networkip: &NetworkIP) -> String {
+    format!(
+        "{}/{}/spec",
+        networkip.metadata.namespace.clone().unwrap(),
+        networkip.metadata.name.clone().unwrap()
+    )
+}
+
 fn get_pod_info_key_name(networkip: &NetworkIP, alloc_name: &str, ip: &IpAddr) -> String {
     let networkip_namespace = networkip.metadata.namespace.clone().unwrap();
     let networkip_name = networkip.metadata.name.clone().unwrap();
@@ -81,8 +99,8 @@ fn get_pod_info_key_name(networkip: &NetworkIP, alloc_name: &str, ip: &IpAddr) -
             "{}/{}/{}/{}",
             networkip_namespace,
             networkip_name,
-            alloc_name.clone(),
-            ip.to_string()
+            alloc_name,
+            ip
         )
 }
 
@@ -91,6 +109,10 @@ pub fn return_ip(
     networkip: &NetworkIP,
     alloc: &NetworkIPAllocations,
     ip: IpAddr) -> redis::RedisResult<()> {
+    // excluded address keeps its bit, e.g. gateway handed out before it was excluded
+    if get_ipallocation_excludes(alloc).contains(&ip) {
+        return Ok(());
+    }
     let bitmap_key = get_bitmap_key_name(networkip, &alloc.name);
     let baseip_key = get_baseip_key_name(networkip, &alloc.name);
 
@@ -98,11 +120,62 @@ pub fn return_ip(
     let baseip: IpAddr = baseip_str.parse().unwrap(); //XXX: may need to change error type, but we
                                                       //may assume that baseip should be valid.
     let index = get_address_index(&baseip, &ip).unwrap();
-    redis::transaction(con, &[bitmap_key.clone()], |con, pipe| {
+    redis::transaction(con, std::slice::from_ref(&bitmap_key), |con, pipe| {
         pipe.setbit(bitmap_key.clone(), index, false).ignore().query(con)
     })
 }
 
+// pod information stored for each address: "<namespace>/<name> <container id>[ <pod uid>]".
+// pod uid is absent in entries written by older versions or without K8S_POD_UID.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PodInformation<'a> {
+    pub namespace: &'a str,
+    pub name: &'a str,
+    pub container_id: &'a str,
+    pub uid: Option<&'a str>,
+}
+
+impl<'a> PodInformation<'a> {
+    pub fn parse(pod_info: &'a str) -> Option<PodInformation<'a>> {
+        let mut fields = pod_info.split(' ');
+        let (namespace, name) = fields.next()?.split_once('/')?;
+        let container_id = fields.next()?;
+        Some(PodInformation {
+            namespace,
+            name,
+            container_id,
+            uid: fields.next(),
+        })
+    }
+}
+
+impl std::fmt::Display for PodInformation<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{} {}", self.namespace, self.name, self.container_id)?;
+        if let Some(uid) = self.uid {
+            write!(f, " {}", uid)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pod_information() {
+    let info = PodInformation {
+        namespace: "default",
+        name: "test-pod",
+        container_id: "0123abcd",
+        uid: Some("5c6a-11"),
+    };
+    assert_eq!(info.to_string(), "default/test-pod 0123abcd 5c6a-11");
+    assert_eq!(PodInformation::parse("default/test-pod 0123abcd 5c6a-11"), Some(info));
+    assert_eq!(
+        PodInformation::parse("default/test-pod 0123abcd").map(|i| (i.container_id, i.uid)),
+        Some(("0123abcd", None))
+    );
+    assert_eq!(PodInformation::parse("Reserved"), None);
+}
+
 pub fn add_pod_information(
     con: &mut redis::Connection,
     networkip: &NetworkIP,
@@ -112,6 +185,43 @@ pub fn add_pod_information(
     con.set(pod_key, pod_info)
 }
 
+pub fn get_pod_information(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    alloc: &NetworkIPAllocations,
+    ip: &IpAddr) -> redis::RedisResult<Option<String>> {
+    let pod_key = get_pod_info_key_name(networkip, &alloc.name, ip);
+    con.get(pod_key)
+}
+
+// returns all (ip, pod information) pairs of the allocation
+pub fn get_pod_informations(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    alloc: &NetworkIPAllocations,
+) -> redis::RedisResult<Vec<(IpAddr, String)>> {
+    let pattern = format!(
+        "{}/{}/{}/*",
+        networkip.metadata.namespace.clone().unwrap(),
+        networkip.metadata.name.clone().unwrap(),
+        alloc.name
+    );
+    let keys: Vec<String> = con.scan_match(pattern)?.collect();
+    let mut pod_infos = vec![];
+    for key in keys {
+        // bitmap/baseip keys are also matched, skip them
+        let ip: IpAddr = match key.rsplit('/').next().and_then(|s| s.parse().ok()) {
+            Some(ip) => ip,
+            None => continue,
+        };
+        let pod_info: Option<String> = con.get(&key)?;
+        if let Some(pod_info) = pod_info {
+            pod_infos.push((ip, pod_info));
+        }
+    }
+    Ok(pod_infos)
+}
+
 pub fn del_pod_information(
     con: &mut redis::Connection,
     networkip: &NetworkIP,
@@ -121,6 +231,69 @@ pub fn del_pod_information(
     con.del(pod_key)
 }
 
+fn get_baseip(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    alloc: &NetworkIPAllocations,
+) -> redis::RedisResult<IpAddr> {
+    let baseip_str: String = con.get(get_baseip_key_name(networkip, &alloc.name))?;
+    baseip_str.parse().map_err(|_| {
+        redis::RedisError::from((redis::ErrorKind::TypeError, "invalid baseip", baseip_str))
+    })
+}
+
+fn get_ip_index(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    alloc: &NetworkIPAllocations,
+    ip: &IpAddr,
+) -> redis::RedisResult<usize> {
+    let baseip = get_baseip(con, networkip, alloc)?;
+    get_address_index(&baseip, ip).map_err(|e| {
+        redis::RedisError::from((redis::ErrorKind::ClientError, "invalid address", e.to_string()))
+    })
+}
+
+pub fn is_ip_used(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    alloc: &NetworkIPAllocations,
+    ip: &IpAddr,
+) -> redis::RedisResult<bool> {
+    let index = get_ip_index(con, networkip, alloc, ip)?;
+    con.getbit(get_bitmap_key_name(networkip, &alloc.name), index)
+}
+
+// mark the ip as used. returns false if the ip is already used.
+pub fn reserve_ip(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    alloc: &NetworkIPAllocations,
+    ip: &IpAddr,
+) -> redis::RedisResult<bool> {
+    let bitmap_key = get_bitmap_key_name(networkip, &alloc.name);
+    let index = get_ip_index(con, networkip, alloc, ip)?;
+    loop {
+        redis::cmd("WATCH").arg(bitmap_key.clone()).query::<()>(con)?;
+
+        let used: bool = con.getbit(bitmap_key.clone(), index)?;
+        if used {
+            redis::cmd("UNWATCH").query::<()>(con)?;
+            return Ok(false);
+        }
+        let response: Option<(usize,)> = redis::pipe()
+            .atomic()
+            .cmd("SETBIT")
+            .arg(bitmap_key.clone())
+            .arg(index)
+            .arg(1u8)
+            .query(con)?;
+        if response.is_some() {
+            return Ok(true);
+        }
+    }
+}
+
 pub fn get_first_available_ip(
     con: &mut redis::Connection,
     networkip: &NetworkIP,
@@ -132,10 +305,19 @@ pub fn get_first_available_ip(
     let baseip_str:String = con.get(baseip_key)?;
     let baseip: IpAddr = baseip_str.parse().unwrap(); //XXX: may need to change error type, but we
                                                       //may assume that baseip should be valid.
+    let capacity = get_ipallocation_capacity(alloc);
     loop {
-        redis::cmd("WATCH").arg(bitmap_key.clone()).query(con)?;
+        redis::cmd("WATCH").arg(bitmap_key.clone()).query::<()>(con)?;
 
         let index_u: usize = redis::cmd("BITPOS").arg(bitmap_key.clone()).arg(0u8).query(con)?;
+        if index_u as u64 >= capacity {
+            redis::cmd("UNWATCH").query::<()>(con)?;
+            return Err(redis::RedisError::from((
+                redis::ErrorKind::ClientError,
+                "no available address",
+                alloc.name.clone(),
+            )));
+        }
         let response: Option<(usize,)> = redis::pipe()
             .atomic()
             .cmd("SETBIT")
@@ -150,11 +332,11 @@ pub fn get_first_available_ip(
             Some(_response) => {
                 return Ok(match baseip {
                     V4(v4addr) => {
-                        let index: u32 = usize::try_from(index_u).unwrap().try_into().unwrap();
+                        let index: u32 = index_u.try_into().unwrap();
                         V4(v4addr.saturating_add(index))
                     },
                     V6(v6addr) => {
-                        let index: u128 = usize::try_from(index_u).unwrap().try_into().unwrap();
+                        let index: u128 = index_u.try_into().unwrap();
                         V6(v6addr.saturating_add(index))
                     }
                 })
@@ -164,6 +346,101 @@ pub fn get_first_available_ip(
 
 }
 
+fn get_ip_from_index(baseip: &IpAddr, index: usize) -> IpAddr {
+    match baseip {
+        V4(v4addr) => V4(v4addr.saturating_add(index as u32)),
+        V6(v6addr) => V6(v6addr.saturating_add(index as u128)),
+    }
+}
+
+// first clear bit of bitmap bytes. Bytes beyond the string are clear in redis.
+fn find_clear_bit(bytes: &[u8], len: usize) -> Option<usize> {
+    (0..len).find(|i| bytes.get(i / 8).is_none_or(|b| b & (0x80 >> (i % 8)) == 0))
+}
+
+#[test]
+fn test_find_clear_bit() {
+    assert_eq!(find_clear_bit(&[0xff, 0xfe], 16), Some(15));
+    assert_eq!(find_clear_bit(&[0xff], 16), Some(8));
+    assert_eq!(find_clear_bit(&[0xff, 0xff], 16), None);
+    assert_eq!(find_clear_bit(&[0xf0], 4), None);
+}
+
+// set a clear bit in the block. SETBIT returns the old value, so no WATCH is
+// required: the bit is ours if it was clear.
+fn allocate_in_block(
+    con: &mut redis::Connection,
+    bitmap_key: &str,
+    block: usize,
+    block_size: usize,
+    capacity: usize,
+) -> redis::RedisResult<Option<usize>> {
+    let start = block * block_size;
+    let len = block_size.min(capacity.saturating_sub(start));
+    loop {
+        let bytes: Vec<u8> = con.getrange(bitmap_key, (start / 8) as isize, ((start + len - 1) / 8) as isize)?;
+        let index = match find_clear_bit(&bytes, len) {
+            Some(i) => start + i,
+            None => return Ok(None),
+        };
+        let used: u8 = con.setbit(bitmap_key, index, true)?;
+        if used == 0 {
+            return Ok(Some(index));
+        }
+    }
+}
+
+// block affinity allocation: use blocks claimed by the node first, then claim a
+// new block, then borrow from blocks of other nodes.
+pub fn get_block_available_ip(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    alloc: &NetworkIPAllocations,
+    block_size: u32,
+    node: &str,
+) -> redis::RedisResult<IpAddr> {
+    let bitmap_key = get_bitmap_key_name(networkip, &alloc.name);
+    let blocks_key = get_blocks_key_name(networkip, &alloc.name);
+    let baseip = get_baseip(con, networkip, alloc)?;
+    let capacity = get_ipallocation_capacity(alloc) as usize;
+    let block_size = block_size as usize;
+    let num_blocks = capacity.div_ceil(block_size);
+
+    let owners: std::collections::HashMap<usize, String> = con.hgetall(&blocks_key)?;
+    let mut own: Vec<usize> = owners.iter().filter(|(_, n)| *n == node).map(|(b, _)| *b).collect();
+    own.sort_unstable();
+    for block in own {
+        if let Some(index) = allocate_in_block(con, &bitmap_key, block, block_size, capacity)? {
+            return Ok(get_ip_from_index(&baseip, index));
+        }
+    }
+
+    for block in (0..num_blocks).filter(|b| !owners.contains_key(b)) {
+        // another node may claim it in the meantime
+        let claimed: bool = con.hset_nx(&blocks_key, block, node)?;
+        if !claimed {
+            continue;
+        }
+        if let Some(index) = allocate_in_block(con, &bitmap_key, block, block_size, capacity)? {
+            return Ok(get_ip_from_index(&baseip, index));
+        }
+    }
+
+    let owners: std::collections::HashMap<usize, String> = con.hgetall(&blocks_key)?;
+    let mut others: Vec<usize> = owners.into_iter().filter(|(_, n)| n != node).map(|(b, _)| b).collect();
+    others.sort_unstable();
+    for block in others {
+        if let Some(index) = allocate_in_block(con, &bitmap_key, block, block_size, capacity)? {
+            return Ok(get_ip_from_index(&baseip, index));
+        }
+    }
+    Err(redis::RedisError::from((
+        redis::ErrorKind::ClientError,
+        "no available address",
+        alloc.name.clone(),
+    )))
+}
+
 pub fn create_network_bitmap(
     con: &mut redis::Connection,
     networkip: &NetworkIP,
@@ -174,15 +451,74 @@ pub fn create_network_bitmap(
         let first_ip = get_ipallocation_baseip(&alloc);
         let _: () = con.set(baseip_key_name, first_ip.to_string())?;
 
-        for exclude_ip in alloc.exclude.clone() {
+        let mut pipe = redis::pipe();
+        for exclude_ip in get_ipallocation_excludes(&alloc) {
             let idx = get_address_index(&first_ip, &exclude_ip).unwrap();
             //eprintln!("set bit {}", idx);
-            let _: () = con.setbit(bitmap_key_name.clone(), idx, true)?;
+            pipe.setbit(bitmap_key_name.clone(), idx, true).ignore();
         }
+        pipe.query::<()>(con)?;
     };
+    set_network_spec(con, networkip)
+}
+
+// mark excluded addresses as used in existing pool. Pools created before auto
+// exclude (or before 'exclude' is updated) have them clear, because excludes are
+// otherwise applied only in create_network_bitmap(). Allocations whose baseip
+// differs from the spec are out of sync and left as is.
+pub fn mark_excluded_ips(con: &mut redis::Connection, networkip: &NetworkIP) -> redis::RedisResult<()> {
+    for alloc in networkip.spec.ip_allocations.iter() {
+        let baseip = get_ipallocation_baseip(alloc);
+        let stored: Option<String> = con.get(get_baseip_key_name(networkip, &alloc.name))?;
+        if stored.and_then(|s| s.parse::<IpAddr>().ok()) != Some(baseip) {
+            continue;
+        }
+        let bitmap_key_name = get_bitmap_key_name(networkip, &alloc.name);
+        let mut pipe = redis::pipe();
+        for exclude_ip in get_ipallocation_excludes(alloc) {
+            let idx = get_address_index(&baseip, &exclude_ip).unwrap();
+            pipe.setbit(bitmap_key_name.clone(), idx, true).ignore();
+        }
+        pipe.query::<()>(con)?;
+    }
     Ok(())
 }
 
+fn serialize_spec(networkip: &NetworkIP) -> redis::RedisResult<String> {
+    serde_json::to_string(&networkip.spec).map_err(|e| {
+        redis::RedisError::from((redis::ErrorKind::TypeError, "failed to serialize spec", e.to_string()))
+    })
+}
+
+// store the spec next to the bitmaps, so that DEL can release addresses
+// without kubernetes
+pub fn set_network_spec(con: &mut redis::Connection, networkip: &NetworkIP) -> redis::RedisResult<()> {
+    con.set(get_spec_key_name(networkip), serialize_spec(networkip)?)
+}
+
+// true if the stored spec is same as the one of 'networkip'
+pub fn check_network_spec(con: &mut redis::Connection, networkip: &NetworkIP) -> redis::RedisResult<bool> {
+    let stored: Option<String> = con.get(get_spec_key_name(networkip))?;
+    Ok(stored == Some(serialize_spec(networkip)?))
+}
+
+// returns NetworkIP of '<namespace>/<name>' built from the spec stored by set_network_spec
+pub fn get_stored_networkip(
+    con: &mut redis::Connection,
+    networkip_namespacedname: &str,
+) -> Result<Option<NetworkIP>> {
+    let (namespace, name) = parse_namespacedname(networkip_namespacedname)?;
+    let spec: Option<String> = con.get(format!("{}/{}/spec", namespace, name))?;
+    match spec {
+        Some(v) => {
+            let mut networkip = NetworkIP::new(name, serde_json::from_str(&v)?);
+            networkip.metadata.namespace = Some(namespace.to_string());
+            Ok(Some(networkip))
+        }
+        None => Ok(None),
+    }
+}
+
 pub fn check_network_bitmap(
     con: &mut redis::Connection,
     networkip: &NetworkIP,
@@ -193,3 +529,237 @@ pub fn check_network_bitmap(
     //con.exists(get_ipallocation_names(networkip))
 }
 
+
+pub fn get_allocation_status(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    alloc: &NetworkIPAllocations,
+) -> redis::RedisResult<NetworkIPAllocationStatus> {
+    let bitmap_key = get_bitmap_key_name(networkip, &alloc.name);
+    let used: u64 = redis::cmd("BITCOUNT").arg(bitmap_key).query(con)?;
+    let capacity = get_ipallocation_capacity(alloc);
+    let excluded = get_ipallocation_excludes(alloc).len() as u64;
+    let allocated = used.saturating_sub(excluded);
+
+    Ok(NetworkIPAllocationStatus {
+        name: alloc.name.clone(),
+        capacity,
+        allocated,
+        free: capacity.saturating_sub(excluded).saturating_sub(allocated),
+        excluded,
+    })
+}
+
+// check whether each allocation is initialized in redis with the baseip in the spec
+pub fn check_network_sync(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+) -> redis::RedisResult<bool> {
+    for alloc in networkip.spec.ip_allocations.iter() {
+        let baseip_key = get_baseip_key_name(networkip, &alloc.name);
+        let baseip_str: Option<String> = con.get(baseip_key)?;
+        match baseip_str.and_then(|s| s.parse::<IpAddr>().ok()) {
+            Some(baseip) if baseip == get_ipallocation_baseip(alloc) => {}
+            _ => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+pub fn get_network_status(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+) -> redis::RedisResult<NetworkIPStatus> {
+    let allocations = networkip
+        .spec
+        .ip_allocations
+        .iter()
+        .map(|alloc| get_allocation_status(con, networkip, alloc))
+        .collect::<redis::RedisResult<Vec<NetworkIPAllocationStatus>>>()?;
+    let in_sync = check_network_sync(con, networkip)?;
+    Ok(NetworkIPStatus::new(networkip, allocations, in_sync))
+}
+
+// node of each block, by block index (string, as redis hash field)
+pub type BlockOwners = BTreeMap<String, String>;
+
+// returns raw (baseip, bitmap, block owners) of the allocation. baseip is None if
+// the allocation is not initialized.
+pub fn get_allocation_state(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    alloc: &NetworkIPAllocations,
+) -> redis::RedisResult<(Option<String>, Vec<u8>, BlockOwners)> {
+    let baseip: Option<String> = con.get(get_baseip_key_name(networkip, &alloc.name))?;
+    let bitmap: Option<Vec<u8>> = con.get(get_bitmap_key_name(networkip, &alloc.name))?;
+    let blocks: BlockOwners = con.hgetall(get_blocks_key_name(networkip, &alloc.name))?;
+    Ok((baseip, bitmap.unwrap_or_default(), blocks))
+}
+
+// write raw baseip, bitmap, block owners and pod informations of the allocation at once
+pub fn set_allocation_state(
+    con: &mut redis::Connection,
+    networkip: &NetworkIP,
+    alloc: &NetworkIPAllocations,
+    baseip: &str,
+    bitmap: &[u8],
+    blocks: &BlockOwners,
+    pod_infos: &[(IpAddr, String)],
+) -> redis::RedisResult<()> {
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .set(get_baseip_key_name(networkip, &alloc.name), baseip)
+        .ignore()
+        .set(get_bitmap_key_name(networkip, &alloc.name), bitmap)
+        .ignore();
+    for (block, node) in blocks {
+        pipe.hset(get_blocks_key_name(networkip, &alloc.name), block, node)
+            .ignore();
+    }
+    for (ip, pod_info) in pod_infos {
+        pipe.set(get_pod_info_key_name(networkip, &alloc.name, ip), pod_info)
+            .ignore();
+    }
+    pipe.query(con)
+}
+
+// keys which do not belong to a pool have this prefix. ':' is not allowed in
+// kubernetes names, so they never conflict with pool keys ('<namespace>/<name>/...')
+pub const KEY_PREFIX: &str = "differance:";
+
+pub fn get_global_key_name(name: &str) -> String {
+    format!("{}{}", KEY_PREFIX, name)
+}
+
+fn get_networkip_cache_key_name(networkip_namespacedname: &str) -> String {
+    get_global_key_name(&format!("cache:networkip:{}", networkip_namespacedname))
+}
+
+// returns cached NetworkIP of '<namespace>/<name>'. Broken entry is taken as miss.
+pub fn get_cached_networkip(
+    con: &mut redis::Connection,
+    networkip_namespacedname: &str,
+) -> Result<Option<NetworkIP>> {
+    let (namespace, name) = parse_namespacedname(networkip_namespacedname)?;
+    let cached: Option<String> = con.get(get_networkip_cache_key_name(&format!("{}/{}", namespace, name)))?;
+    Ok(cached.and_then(|v| serde_json::from_str(&v).ok()))
+}
+
+// cache NetworkIP for 'ttl' seconds
+pub fn set_cached_networkip(con: &mut redis::Connection, networkip: &NetworkIP, ttl: usize) -> Result<()> {
+    let key = get_networkip_cache_key_name(&format!(
+        "{}/{}",
+        networkip.metadata.namespace.clone().unwrap_or_default(),
+        networkip.metadata.name.clone().unwrap_or_default()
+    ));
+    con.set_ex::<_, _, ()>(key, serde_json::to_string(networkip)?, ttl)?;
+    Ok(())
+}
+
+// revalidate cached NetworkIP: replace it by the given one if its resourceVersion
+// differs. The entry keeps its remaining TTL, and no entry is created.
+pub fn refresh_cached_networkip(con: &mut redis::Connection, networkip: &NetworkIP) -> Result<()> {
+    let namespacedname = format!(
+        "{}/{}",
+        networkip.metadata.namespace.clone().unwrap_or_default(),
+        networkip.metadata.name.clone().unwrap_or_default()
+    );
+    if let Some(cached) = get_cached_networkip(con, &namespacedname)? {
+        if cached.metadata.resource_version != networkip.metadata.resource_version {
+            let key = get_networkip_cache_key_name(&namespacedname);
+            let ttl: i64 = con.ttl(&key)?;
+            if ttl > 0 {
+                con.set_ex::<_, _, ()>(key, serde_json::to_string(networkip)?, ttl as usize)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// one container may have several attachments (e.g. net1 and net2 by multus), so
+// the record is per interface and network configuration
+fn get_container_network_key_name(container_id: &str, ifname: &str, netconf_name: &str) -> String {
+    get_global_key_name(&format!("container:{}/{}/{}", container_id, ifname, netconf_name))
+}
+
+// (container id, ifname, netconf name) of the key
+fn parse_container_network_key_name(key: &str) -> Option<(&str, &str, &str)> {
+    let mut parts = key.strip_prefix(KEY_PREFIX)?.strip_prefix("container:")?.splitn(3, '/');
+    Some((parts.next()?, parts.next()?, parts.next()?))
+}
+
+#[test]
+fn test_container_network_key_name() {
+    let net1 = get_container_network_key_name("0123abcd", "net1", "macvlan-a");
+    let net2 = get_container_network_key_name("0123abcd", "net2", "macvlan-b");
+    assert_eq!(net1, "differance:container:0123abcd/net1/macvlan-a");
+    assert_ne!(net1, net2);
+    assert_ne!(net1, get_container_network_key_name("0123abcd", "net1", "macvlan-b"));
+    assert_eq!(
+        parse_container_network_key_name(&net1),
+        Some(("0123abcd", "net1", "macvlan-a"))
+    );
+    assert_eq!(parse_container_network_key_name("differance:container:0123abcd"), None);
+}
+
+// remember networkip the attachment got addresses from, because pod annotation
+// may not be available in DEL
+pub fn set_container_network(
+    con: &mut redis::Connection,
+    container_id: &str,
+    ifname: &str,
+    netconf_name: &str,
+    network: &str,
+) -> redis::RedisResult<()> {
+    con.set(get_container_network_key_name(container_id, ifname, netconf_name), network)
+}
+
+pub fn get_container_network(
+    con: &mut redis::Connection,
+    container_id: &str,
+    ifname: &str,
+    netconf_name: &str,
+) -> redis::RedisResult<Option<String>> {
+    con.get(get_container_network_key_name(container_id, ifname, netconf_name))
+}
+
+pub fn del_container_network(
+    con: &mut redis::Connection,
+    container_id: &str,
+    ifname: &str,
+    netconf_name: &str,
+) -> redis::RedisResult<()> {
+    con.del(get_container_network_key_name(container_id, ifname, netconf_name))
+}
+
+// record of set_container_network()
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContainerNetwork {
+    pub container_id: String,
+    pub ifname: String,
+    pub netconf_name: String,
+    /// network specifies networkips, separated by ','
+    pub network: String,
+}
+
+// returns all records of set_container_network()
+pub fn get_container_networks(con: &mut redis::Connection) -> redis::RedisResult<Vec<ContainerNetwork>> {
+    let keys: Vec<String> = con.scan_match(get_global_key_name("container:*"))?.collect();
+    let mut networks = vec![];
+    for key in keys.iter() {
+        let (container_id, ifname, netconf_name) = match parse_container_network_key_name(key) {
+            Some(v) => v,
+            None => continue,
+        };
+        let network: Option<String> = con.get(key)?;
+        if let Some(network) = network {
+            networks.push(ContainerNetwork {
+                container_id: container_id.to_string(),
+                ifname: ifname.to_string(),
+                netconf_name: netconf_name.to_string(),
+                network,
+            });
+        }
+    }
+    Ok(networks)
+}
@@ -0,0 +1,367 @@
+// validation.rs: NetworkIP spec validation shared by plugin, controller and webhook
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use serde_json::Value;
+
+use crate::kube_crd::{get_ipallocation_capacity, NetworkIPAllocations, NetworkIPSpec};
+
+// redis bitmap offset is limited to 2^32
+const MAX_CAPACITY: u64 = 1 << 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &str, message: String) -> FieldError {
+        FieldError {
+            field: field.to_string(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_fmt(format_args!("{}: {}", self.field, self.message))
+    }
+}
+
+pub fn join_errors(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+fn get_ip(value: &Value, field: &str, errors: &mut Vec<FieldError>) -> Option<IpAddr> {
+    match value.as_str().map(|s| (s, s.parse::<IpAddr>())) {
+        Some((_, Ok(ip))) => Some(ip),
+        Some((s, Err(_))) => {
+            errors.push(FieldError::new(field, format!("invalid IP address '{}'", s)));
+            None
+        }
+        None => {
+            errors.push(FieldError::new(field, "IP address must be a string".to_string()));
+            None
+        }
+    }
+}
+
+fn get_cidr(value: &Value, field: &str, errors: &mut Vec<FieldError>) -> Option<IpNet> {
+    match value.as_str().map(|s| (s, s.parse::<IpNet>())) {
+        Some((_, Ok(net))) => Some(net),
+        Some((s, Err(_))) => {
+            errors.push(FieldError::new(field, format!("invalid CIDR '{}'", s)));
+            None
+        }
+        None => {
+            errors.push(FieldError::new(field, "CIDR must be a string".to_string()));
+            None
+        }
+    }
+}
+
+fn validate_allocation(
+    path: &str,
+    alloc: &Value,
+    names: &mut HashSet<String>,
+    errors: &mut Vec<FieldError>,
+    warnings: &mut Vec<FieldError>,
+) {
+    let num_errors = errors.len();
+
+    match alloc.get("name").and_then(Value::as_str) {
+        Some("") | None => errors.push(FieldError::new(
+            &format!("{}.name", path),
+            "name is required".to_string(),
+        )),
+        Some(name) => {
+            if !names.insert(name.to_string()) {
+                errors.push(FieldError::new(
+                    &format!("{}.name", path),
+                    format!("duplicate allocation name '{}'", name),
+                ));
+            }
+        }
+    }
+
+    let subnet = match alloc.get("subnet") {
+        Some(v) => get_cidr(v, &format!("{}.subnet", path), errors),
+        None => {
+            errors.push(FieldError::new(
+                &format!("{}.subnet", path),
+                "subnet is required".to_string(),
+            ));
+            None
+        }
+    };
+    // check 'ip' is in subnet, if subnet is valid
+    let in_subnet = |ip: &IpAddr, field: &str, errors: &mut Vec<FieldError>| {
+        if let Some(net) = subnet {
+            if !net.contains(ip) {
+                errors.push(FieldError::new(
+                    field,
+                    format!("{} is not in subnet {}", ip, net),
+                ));
+            }
+        }
+    };
+
+    if let Some(v) = alloc.get("gateway") {
+        let field = format!("{}.gateway", path);
+        if let Some(gw) = get_ip(v, &field, errors) {
+            in_subnet(&gw, &field, errors);
+        }
+    }
+
+    let mut start = None;
+    let mut end = None;
+    if let Some(range) = alloc.get("range") {
+        let field = format!("{}.range.start", path);
+        match range.get("start") {
+            Some(v) => {
+                start = get_ip(v, &field, errors);
+                if let Some(ip) = start {
+                    in_subnet(&ip, &field, errors);
+                }
+            }
+            None => errors.push(FieldError::new(&field, "start is required".to_string())),
+        }
+        if let Some(v) = range.get("end") {
+            let field = format!("{}.range.end", path);
+            end = get_ip(v, &field, errors);
+            if let Some(ip) = end {
+                in_subnet(&ip, &field, errors);
+                if let Some(s) = start {
+                    if ip < s {
+                        errors.push(FieldError::new(
+                            &field,
+                            format!("end {} is before start {}", ip, s),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(excludes) = alloc.get("exclude").and_then(Value::as_array) {
+        for (i, v) in excludes.iter().enumerate() {
+            let field = format!("{}.exclude[{}]", path, i);
+            if let Some(ip) = get_ip(v, &field, errors) {
+                in_subnet(&ip, &field, errors);
+                // harmless, such address is never allocated anyway
+                if start.is_some_and(|s| ip < s) || end.is_some_and(|e| ip > e) {
+                    warnings.push(FieldError::new(&field, format!("{} is not in range, ignored", ip)));
+                }
+            }
+        }
+    }
+
+    if let Some(routes) = alloc.get("route").and_then(Value::as_array) {
+        for (i, route) in routes.iter().enumerate() {
+            let field = format!("{}.route[{}]", path, i);
+            match route.get("dst") {
+                Some(v) => {
+                    get_cidr(v, &format!("{}.dst", field), errors);
+                }
+                None => errors.push(FieldError::new(
+                    &format!("{}.dst", field),
+                    "dst is required".to_string(),
+                )),
+            }
+            match route.get("gw") {
+                Some(v) => {
+                    get_ip(v, &format!("{}.gw", field), errors);
+                }
+                None => errors.push(FieldError::new(
+                    &format!("{}.gw", field),
+                    "gw is required".to_string(),
+                )),
+            }
+        }
+    }
+
+    if let Some(v) = alloc.get("blockSize") {
+        let field = format!("{}.blockSize", path);
+        match v.as_u64() {
+            Some(size) if size > 0 && size % 8 == 0 => {}
+            _ => errors.push(FieldError::new(
+                &field,
+                format!("{} is not a positive multiple of 8", v),
+            )),
+        }
+    }
+
+    // capacity can be computed only if above fields are valid
+    if errors.len() == num_errors {
+        if let Ok(alloc) = serde_json::from_value::<NetworkIPAllocations>(alloc.clone()) {
+            if get_ipallocation_capacity(&alloc) > MAX_CAPACITY {
+                warnings.push(FieldError::new(
+                    path,
+                    format!("range is too large, only the first {} addresses are used", MAX_CAPACITY),
+                ));
+            }
+        }
+    }
+}
+
+// validate NetworkIP spec in JSON form. JSON form is used to report invalid
+// addresses, which cannot be deserialized into NetworkIPSpec, field by field.
+pub fn validate_spec_value(spec: &Value) -> Vec<FieldError> {
+    validate_spec_value_with_warnings(spec).0
+}
+
+// same as validate_spec_value, and also returns warnings, which do not make the
+// spec invalid
+pub fn validate_spec_value_with_warnings(spec: &Value) -> (Vec<FieldError>, Vec<FieldError>) {
+    let mut errors = vec![];
+    let mut warnings = vec![];
+    let allocs = match spec.get("ipAllocations").and_then(Value::as_array) {
+        Some(v) => v,
+        None => {
+            errors.push(FieldError::new(
+                "spec.ipAllocations",
+                "ipAllocations is required".to_string(),
+            ));
+            return (errors, warnings);
+        }
+    };
+    let mut names = HashSet::new();
+    for (i, alloc) in allocs.iter().enumerate() {
+        validate_allocation(
+            &format!("spec.ipAllocations[{}]", i),
+            alloc,
+            &mut names,
+            &mut errors,
+            &mut warnings,
+        );
+    }
+    if let Some(nameservers) = spec
+        .get("dns")
+        .and_then(|dns| dns.get("nameservers"))
+        .and_then(Value::as_array)
+    {
+        for (i, v) in nameservers.iter().enumerate() {
+            get_ip(v, &format!("spec.dns.nameservers[{}]", i), &mut errors);
+        }
+    }
+    if let Some(expressions) = spec
+        .get("namespaceSelector")
+        .and_then(|selector| selector.get("matchExpressions"))
+        .and_then(Value::as_array)
+    {
+        for (i, e) in expressions.iter().enumerate() {
+            let field = format!("spec.namespaceSelector.matchExpressions[{}]", i);
+            let values = e.get("values").and_then(Value::as_array).map_or(0, |v| v.len());
+            match e.get("operator").and_then(Value::as_str) {
+                Some("In") | Some("NotIn") if values == 0 => errors.push(FieldError::new(
+                    &format!("{}.values", field),
+                    "values must be non-empty for In and NotIn".to_string(),
+                )),
+                Some("Exists") | Some("DoesNotExist") if values != 0 => errors.push(FieldError::new(
+                    &format!("{}.values", field),
+                    "values must be empty for Exists and DoesNotExist".to_string(),
+                )),
+                Some("In") | Some("NotIn") | Some("Exists") | Some("DoesNotExist") => {}
+                v => errors.push(FieldError::new(
+                    &format!("{}.operator", field),
+                    format!("unknown operator: {}", v.unwrap_or_default()),
+                )),
+            }
+        }
+    }
+    (errors, warnings)
+}
+
+pub fn validate_spec(spec: &NetworkIPSpec) -> Vec<FieldError> {
+    match serde_json::to_value(spec) {
+        Ok(v) => validate_spec_value(&v),
+        Err(e) => vec![FieldError::new("spec", e.to_string())],
+    }
+}
+
+#[test]
+fn test_validate_spec_value_valid() {
+    let spec = serde_json::json!({
+        "ipAllocations": [{
+            "name": "testIPv4",
+            "subnet": "10.1.1.0/24",
+            "gateway": "10.1.1.254",
+            "range": { "start": "10.1.1.100", "end": "10.1.1.250" },
+            "exclude": ["10.1.1.200"],
+            "route": [{ "dst": "0.0.0.0/0", "gw": "10.1.1.1" }],
+        }],
+    });
+    assert!(validate_spec_value(&spec).is_empty());
+}
+
+#[test]
+fn test_validate_spec_value_invalid() {
+    let spec = serde_json::json!({
+        "ipAllocations": [{
+            "name": "testIPv6",
+            "subnet": "10::1/64",
+            "gateway": "10::1::fe",
+            "range": { "start": "10::1:1", "end": "11::1" },
+            "exclude": ["10::2:0:0:0:1"],
+            "route": [{ "dst": "10::", "gw": "10::1" }],
+            "blockSize": 12,
+        }, {
+            "name": "testIPv6",
+            "subnet": "10::1/120",
+        }],
+        "namespaceSelector": {
+            "matchExpressions": [{ "key": "tenant", "operator": "Equals", "values": ["a"] }],
+        },
+    });
+    let errors: Vec<String> = validate_spec_value(&spec)
+        .iter()
+        .map(|e| e.field.clone())
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            "spec.ipAllocations[0].gateway",
+            "spec.ipAllocations[0].range.end",
+            "spec.ipAllocations[0].exclude[0]",
+            "spec.ipAllocations[0].route[0].dst",
+            "spec.ipAllocations[0].blockSize",
+            "spec.ipAllocations[1].name",
+            "spec.namespaceSelector.matchExpressions[0].operator",
+        ]
+    );
+}
+
+#[test]
+fn test_validate_spec_value_too_large() {
+    let spec = serde_json::json!({
+        "ipAllocations": [{ "name": "testIPv6", "subnet": "10::/64" }],
+    });
+    let (errors, warnings) = validate_spec_value_with_warnings(&spec);
+    assert!(errors.is_empty());
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].field, "spec.ipAllocations[0]");
+}
+
+#[test]
+fn test_validate_spec_value_exclude_not_in_range() {
+    let spec = serde_json::json!({
+        "ipAllocations": [{
+            "name": "testIPv4",
+            "subnet": "10.1.1.0/24",
+            "range": { "start": "10.1.1.100", "end": "10.1.1.250" },
+            "exclude": ["10.1.1.200", "10.1.1.254"],
+        }],
+    });
+    let (errors, warnings) = validate_spec_value_with_warnings(&spec);
+    assert!(errors.is_empty());
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].field, "spec.ipAllocations[0].exclude[1]");
+}
@@ -0,0 +1,145 @@
+// webhook.rs: validating admission webhook for NetworkIP
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use anyhow::Result;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use kube::core::{
+    admission::{AdmissionRequest, AdmissionResponse, AdmissionReview},
+    DynamicObject,
+};
+use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod};
+use serde_json::Value;
+use tokio::net::TcpListener;
+use tokio_openssl::SslStream;
+use tracing::{info, warn};
+
+use crate::validation::{join_errors, validate_spec_value_with_warnings};
+
+pub const VALIDATE_PATH: &str = "/validate";
+
+pub fn review(req: &AdmissionRequest<DynamicObject>) -> AdmissionResponse {
+    let resp = AdmissionResponse::from(req);
+    let obj = match &req.object {
+        Some(obj) => obj,
+        // DELETE has no object
+        None => return resp,
+    };
+    let (errors, warnings) =
+        validate_spec_value_with_warnings(obj.data.get("spec").unwrap_or(&Value::Null));
+    let mut resp = resp;
+    if !warnings.is_empty() {
+        resp.warnings = Some(warnings.iter().map(|w| w.to_string()).collect());
+    }
+    if errors.is_empty() {
+        resp
+    } else {
+        resp.deny(join_errors(&errors))
+    }
+}
+
+#[test]
+fn test_review() {
+    let admission_review: AdmissionReview<DynamicObject> = serde_json::from_value(serde_json::json!({
+        "apiVersion": "admission.k8s.io/v1",
+        "kind": "AdmissionReview",
+        "request": {
+            "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+            "kind": {"group": "xxxx.cni.cncf.io", "version": "v1alpha1", "kind": "NetworkIP"},
+            "resource": {"group": "xxxx.cni.cncf.io", "version": "v1alpha1", "resource": "networkips"},
+            "name": "test-network-ipv6",
+            "namespace": "default",
+            "operation": "CREATE",
+            "userInfo": {},
+            "object": {
+                "apiVersion": "xxxx.cni.cncf.io/v1alpha1",
+                "kind": "NetworkIP",
+                "metadata": {"name": "test-network-ipv6"},
+                "spec": {"ipAllocations": [{
+                    "name": "testIPv6",
+                    "subnet": "10::1/64",
+                    "gateway": "10::1::fe",
+                }]},
+            },
+            "dryRun": false,
+        },
+    }))
+    .unwrap();
+    let req: AdmissionRequest<DynamicObject> = admission_review.try_into().unwrap();
+    let resp = review(&req);
+    assert!(!resp.allowed);
+    assert_eq!(
+        resp.result.message.unwrap(),
+        "spec.ipAllocations[0].gateway: invalid IP address '10::1::fe'"
+    );
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::POST || req.uri().path() != VALIDATE_PATH {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap());
+    }
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(b) => b,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(e.to_string()))
+                .unwrap())
+        }
+    };
+    let resp = match serde_json::from_slice::<AdmissionReview<DynamicObject>>(&body)
+        .map_err(|e| e.to_string())
+        .and_then(|r| {
+            TryInto::<AdmissionRequest<DynamicObject>>::try_into(r).map_err(|e| e.to_string())
+        }) {
+        Ok(req) => {
+            let resp = review(&req);
+            info!(name = %req.name, namespace = ?req.namespace, allowed = resp.allowed, "reviewed");
+            resp
+        }
+        Err(e) => {
+            warn!("invalid admission review: {}", e);
+            AdmissionResponse::invalid(e)
+        }
+    };
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(&resp.into_review()).unwrap()))
+        .unwrap())
+}
+
+// serve webhook over TLS, as kube-apiserver requires https for webhooks
+pub async fn serve(addr: SocketAddr, tls_cert: &str, tls_key: &str) -> Result<()> {
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    acceptor.set_private_key_file(tls_key, SslFiletype::PEM)?;
+    acceptor.set_certificate_chain_file(tls_cert)?;
+    let acceptor = acceptor.build();
+
+    let listener = TcpListener::bind(addr).await?;
+    info!("listening on {}", addr);
+    loop {
+        let (tcp, peer) = listener.accept().await?;
+        let ssl = Ssl::new(acceptor.context())?;
+        tokio::spawn(async move {
+            let mut stream = match SslStream::new(ssl, tcp) {
+                Ok(s) => s,
+                Err(e) => return warn!("failed to create TLS stream for {}: {}", peer, e),
+            };
+            if let Err(e) = Pin::new(&mut stream).accept().await {
+                return warn!("TLS handshake with {} failed: {}", peer, e);
+            }
+            if let Err(e) = Http::new()
+                .serve_connection(stream, service_fn(handle))
+                .await
+            {
+                warn!("failed to serve {}: {}", peer, e);
+            }
+        });
+    }
+}